@@ -0,0 +1,20 @@
+-- Crowdsourced observations submitted through /v2/geosubmit
+CREATE TABLE IF NOT EXISTS observations (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    -- Same keys as the position cache: wifi:aa:bb:cc:dd:ee:ff or cell:<radio>:<mcc>:<mnc>:<lac>:<cellId>
+    network_key TEXT NOT NULL,
+    lat REAL NOT NULL,
+    lng REAL NOT NULL,
+    -- Position accuracy in meters
+    accuracy REAL,
+    altitude REAL,
+    altitude_accuracy REAL,
+    signal_strength INTEGER,
+    -- Milliseconds between seeing the network and the position fix
+    age INTEGER,
+    -- Milliseconds since the epoch
+    observed_at INTEGER NOT NULL,
+    submitted_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS observations_network_key ON observations (network_key);
@@ -0,0 +1,9 @@
+-- Progress of the scheduled aggregation of observations into the local dataset
+CREATE TABLE IF NOT EXISTS aggregation_state (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    -- Cursor over (latest submitted_at, network_key) of the networks already aggregated
+    last_submitted_at INTEGER NOT NULL,
+    last_network_key TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS observations_submitted_at ON observations (submitted_at);
@@ -0,0 +1,5 @@
+-- Aggregation reads the most recent observations of a network, retention pruning
+-- deletes by age
+CREATE INDEX IF NOT EXISTS observations_network_key_observed_at ON observations (network_key, observed_at);
+CREATE INDEX IF NOT EXISTS observations_observed_at ON observations (observed_at);
+DROP INDEX IF EXISTS observations_network_key;
@@ -0,0 +1,7 @@
+-- Networks the aggregation found to have moved. Their observations made before
+-- moved_at are no longer used, but kept until retention pruning deletes them
+CREATE TABLE IF NOT EXISTS network_moves (
+    network_key TEXT PRIMARY KEY,
+    -- Milliseconds since the epoch
+    moved_at INTEGER NOT NULL
+);
@@ -0,0 +1,376 @@
+// Scheduled aggregation of geosubmit observations into the local dataset
+use serde::Deserialize;
+use worker::wasm_bindgen::JsValue;
+use worker::{console_log, console_warn, D1Database, Date, Env, Result};
+
+use crate::estimate::{estimate_network_position, haversine_distance, Sighting};
+use crate::geosubmit;
+use crate::local_dataset::{DatasetRecord, LocalDataset};
+
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+// Networks aggregated per run, bounded by the subrequest limit of one invocation
+const NETWORKS_PER_RUN: u32 = 100;
+// Most recent sightings of a network used per run, bounding the clustering cost
+const SIGHTINGS_PER_NETWORK: u32 = 200;
+// Sightings newer than this are compared against the older ones to detect moves
+const RECENT_WINDOW_MS: u64 = 7 * DAY_MS;
+// Before an AP or tower is considered moved, the recent sightings have to come from
+// several submissions received over at least a day, so a few forged ones cannot
+// override its history
+const MIN_RECENT_SIGHTINGS: usize = 10;
+const MIN_RECENT_SUBMISSIONS: usize = 5;
+const MIN_RECENT_SPAN_MS: u64 = DAY_MS;
+// Observations are deleted once they are this old, at most PRUNED_PER_RUN per run
+const RETENTION_MS: u64 = 90 * DAY_MS;
+const PRUNED_PER_RUN: u32 = 10_000;
+
+#[derive(Debug, Deserialize)]
+struct Cursor {
+    last_submitted_at: u64,
+    last_network_key: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct UpdatedNetwork {
+    network_key: String,
+    latest: u64,
+    /// When the network was last found to have moved, earlier sightings are stale
+    moved_at: Option<u64>,
+}
+
+/// Outcome of aggregating one network
+#[derive(Debug, PartialEq)]
+enum Aggregate {
+    Position(f64, f64, f64),
+    /// The network moved: sightings before `moved_at` are stale from now on, the
+    /// position is estimated from the recent ones only
+    Moved {
+        moved_at: u64,
+        position: (f64, f64, f64),
+    },
+    /// No current sightings are left to estimate a position from
+    Unknown,
+}
+
+/// Re-estimates the networks with observations submitted since the last run and
+/// writes them to the local dataset, then prunes expired observations
+pub async fn run(env: &Env) -> Result<()> {
+    let Some(db) = geosubmit::database(env) else {
+        console_warn!("Skipping aggregation, no OBSERVATIONS database is bound");
+        return Ok(());
+    };
+    let Some(dataset) = LocalDataset::new(env) else {
+        console_warn!("Skipping aggregation, no LOCAL_DATASET namespace is bound");
+        return Ok(());
+    };
+
+    let cursor = db
+        .prepare("SELECT last_submitted_at, last_network_key FROM aggregation_state WHERE id = 1")
+        .first::<Cursor>(None)
+        .await?
+        .unwrap_or(Cursor {
+            last_submitted_at: 0,
+            last_network_key: String::new(),
+        });
+
+    let networks: Vec<UpdatedNetwork> = db
+        .prepare(
+            "SELECT o.network_key, MAX(o.submitted_at) AS latest, m.moved_at \
+             FROM observations o LEFT JOIN network_moves m ON m.network_key = o.network_key \
+             WHERE o.submitted_at >= ?1 GROUP BY o.network_key \
+             HAVING latest > ?1 OR (latest = ?1 AND o.network_key > ?2) \
+             ORDER BY latest, o.network_key LIMIT ?3",
+        )
+        .bind(&[
+            (cursor.last_submitted_at as f64).into(),
+            cursor.last_network_key.as_str().into(),
+            NETWORKS_PER_RUN.into(),
+        ])?
+        .all()
+        .await?
+        .results()?;
+    let now = Date::now().as_millis();
+    let Some(last) = networks.last() else {
+        return prune(&db, now).await;
+    };
+
+    // Sightings since a move are the newest ones, stale ones only fill up the limit
+    let select = db.prepare(
+        "SELECT lat, lng, accuracy, signal_strength, age, observed_at, submitted_at \
+         FROM observations WHERE network_key = ?1 ORDER BY observed_at DESC LIMIT ?2",
+    );
+    let statements = networks
+        .iter()
+        .map(|n| {
+            select
+                .clone()
+                .bind(&[n.network_key.as_str().into(), SIGHTINGS_PER_NETWORK.into()])
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let results = db.batch(statements).await?;
+
+    let (mut updated, mut moved) = (0, 0);
+    for (network, result) in networks.iter().zip(results) {
+        let mut sightings: Vec<Sighting> = result.results()?;
+        sightings.reverse();
+        let is_wifi = network.network_key.starts_with("wifi:");
+
+        let moved_at = network.moved_at.unwrap_or(0);
+        let position = match aggregate(&sightings, is_wifi, now, moved_at) {
+            Aggregate::Position(lat, lng, accuracy) => Some((lat, lng, accuracy)),
+            Aggregate::Moved { moved_at, position } => {
+                moved += 1;
+                record_move(&db, &network.network_key, moved_at).await?;
+                Some(position)
+            }
+            Aggregate::Unknown => {
+                dataset.remove(&network.network_key).await?;
+                None
+            }
+        };
+        if let Some((lat, lng, accuracy)) = position {
+            let record = DatasetRecord {
+                lat,
+                lng,
+                accuracy,
+                altitude: None,
+                altitude_accuracy: None,
+            };
+            dataset.put(&network.network_key, &record).await?;
+            updated += 1;
+        }
+    }
+
+    db.prepare(
+        "INSERT INTO aggregation_state (id, last_submitted_at, last_network_key) \
+         VALUES (1, ?1, ?2) ON CONFLICT (id) DO UPDATE SET \
+         last_submitted_at = excluded.last_submitted_at, \
+         last_network_key = excluded.last_network_key",
+    )
+    .bind(&[
+        (last.latest as f64).into(),
+        last.network_key.as_str().into(),
+    ])?
+    .run()
+    .await?;
+
+    console_log!(
+        "Aggregated {} networks: {} positions updated, {} moved",
+        networks.len(),
+        updated,
+        moved
+    );
+    prune(&db, now).await
+}
+
+/// Estimates a network's position from its sightings since `moved_at`, sorted by
+/// time. A network whose recent sightings disagree with the older ones by more than
+/// both accuracy radii has moved, and only the recent ones are used. The older
+/// sightings are kept until they expire, but the move is recorded so they are not
+/// used again
+fn aggregate(sightings: &[Sighting], is_wifi: bool, now: u64, moved_at: u64) -> Aggregate {
+    let current = sightings.partition_point(|s| s.observed_at < moved_at);
+    let sightings = &sightings[current..];
+    let cutoff = now.saturating_sub(RECENT_WINDOW_MS);
+    let split = sightings.partition_point(|s| s.observed_at < cutoff);
+    let (older, recent) = sightings.split_at(split);
+
+    if is_corroborated(recent) {
+        let old_position = estimate_network_position(older, is_wifi);
+        let recent_position = estimate_network_position(recent, is_wifi);
+        if let (Some(old), Some(new)) = (old_position, recent_position) {
+            if haversine_distance(old.0, old.1, new.0, new.1) > old.2 + new.2 {
+                return Aggregate::Moved {
+                    moved_at: cutoff,
+                    position: new,
+                };
+            }
+        }
+    }
+
+    match estimate_network_position(sightings, is_wifi) {
+        Some((lat, lng, accuracy)) => Aggregate::Position(lat, lng, accuracy),
+        None => Aggregate::Unknown,
+    }
+}
+
+/// Whether the sightings are enough evidence for a move: many of them, from several
+/// submissions received over a long enough time
+fn is_corroborated(sightings: &[Sighting]) -> bool {
+    let mut submissions: Vec<u64> = sightings.iter().map(|s| s.submitted_at).collect();
+    submissions.sort_unstable();
+    submissions.dedup();
+    let span = match (submissions.first(), submissions.last()) {
+        (Some(first), Some(last)) => last - first,
+        _ => 0,
+    };
+
+    sightings.len() >= MIN_RECENT_SIGHTINGS
+        && submissions.len() >= MIN_RECENT_SUBMISSIONS
+        && span >= MIN_RECENT_SPAN_MS
+}
+
+async fn record_move(db: &D1Database, network_key: &str, moved_at: u64) -> Result<()> {
+    db.prepare(
+        "INSERT INTO network_moves (network_key, moved_at) VALUES (?1, ?2) \
+         ON CONFLICT (network_key) DO UPDATE SET moved_at = excluded.moved_at",
+    )
+    .bind(&[network_key.into(), JsValue::from_f64(moved_at as f64)])?
+    .run()
+    .await?;
+    Ok(())
+}
+
+/// Deletes the oldest observations past `RETENTION_MS`, and the moves no observation
+/// predates any longer
+async fn prune(db: &D1Database, now: u64) -> Result<()> {
+    let expired = JsValue::from_f64(now.saturating_sub(RETENTION_MS) as f64);
+    let observations = db
+        .prepare(
+            "DELETE FROM observations WHERE id IN \
+             (SELECT id FROM observations WHERE observed_at < ?1 ORDER BY observed_at LIMIT ?2)",
+        )
+        .bind(&[expired.clone(), PRUNED_PER_RUN.into()])?;
+    let moves = db
+        .prepare("DELETE FROM network_moves WHERE moved_at < ?1")
+        .bind(&[expired])?;
+    db.batch(vec![observations, moves]).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR_MS: u64 = 60 * 60 * 1000;
+    const NOW: u64 = 100 * DAY_MS;
+    const HOME: (f64, f64) = (52.5200, 13.4050);
+    // Five kilometers north-east, far outside any AP's range
+    const NEW_HOME: (f64, f64) = (52.5520, 13.4570);
+
+    fn sighting(position: (f64, f64), observed_at: u64, submitted_at: u64) -> Sighting {
+        Sighting {
+            lat: position.0,
+            lng: position.1,
+            accuracy: Some(10.0),
+            signal_strength: Some(-60),
+            age: None,
+            observed_at,
+            submitted_at,
+        }
+    }
+
+    /// Twenty sightings at `HOME` a month ago, each from its own submission
+    fn history() -> Vec<Sighting> {
+        (0..20)
+            .map(|i| {
+                let at = NOW - 30 * DAY_MS + i * HOUR_MS;
+                sighting(HOME, at, at)
+            })
+            .collect()
+    }
+
+    /// `count` sightings at `position`, two per submission, submitted over `span`
+    /// ending at `end`
+    fn sightings_at(position: (f64, f64), count: u64, end: u64, span: u64) -> Vec<Sighting> {
+        let submissions = count.div_ceil(2).max(2);
+        (0..count)
+            .map(|i| {
+                let submitted_at = end - span + (i / 2) * span / (submissions - 1);
+                sighting(position, submitted_at, submitted_at)
+            })
+            .collect()
+    }
+
+    fn distance_to(position: (f64, f64), estimate: (f64, f64, f64)) -> f64 {
+        haversine_distance(position.0, position.1, estimate.0, estimate.1)
+    }
+
+    #[test]
+    fn corroboration_needs_sightings_submissions_and_time() {
+        assert!(is_corroborated(&sightings_at(
+            NEW_HOME,
+            10,
+            NOW,
+            2 * DAY_MS
+        )));
+        // Too few sightings
+        assert!(!is_corroborated(&sightings_at(
+            NEW_HOME,
+            9,
+            NOW,
+            2 * DAY_MS
+        )));
+        // Submitted within a few hours
+        assert!(!is_corroborated(&sightings_at(
+            NEW_HOME,
+            10,
+            NOW,
+            3 * HOUR_MS
+        )));
+        // Many sightings, but all in one submission
+        let single: Vec<_> = (0..20)
+            .map(|i| sighting(NEW_HOME, NOW - i * HOUR_MS, NOW))
+            .collect();
+        assert!(!is_corroborated(&single));
+    }
+
+    #[test]
+    fn corroborated_move_is_detected() {
+        let mut sightings = history();
+        sightings.extend(sightings_at(NEW_HOME, 10, NOW, 2 * DAY_MS));
+
+        match aggregate(&sightings, true, NOW, 0) {
+            Aggregate::Moved { moved_at, position } => {
+                assert_eq!(moved_at, NOW - RECENT_WINDOW_MS);
+                assert!(distance_to(NEW_HOME, position) < 50.0);
+            }
+            other => panic!("expected a move, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn uncorroborated_move_keeps_the_history() {
+        let mut sightings = history();
+        // A burst of forged sightings from a single submission
+        sightings.extend((0..12).map(|i| sighting(NEW_HOME, NOW - i * HOUR_MS, NOW)));
+
+        match aggregate(&sightings, true, NOW, 0) {
+            Aggregate::Position(lat, lng, accuracy) => {
+                assert!(distance_to(HOME, (lat, lng, accuracy)) < 50.0);
+            }
+            other => panic!("expected the old position, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn moved_network_does_not_revert() {
+        let mut sightings = history();
+        sightings.extend(sightings_at(NEW_HOME, 10, NOW, 2 * DAY_MS));
+        let Aggregate::Moved { moved_at, .. } = aggregate(&sightings, true, NOW, 0) else {
+            panic!("expected a move");
+        };
+
+        // Two weeks later a single new sighting arrives, the move is out of the window
+        let later = NOW + 14 * DAY_MS;
+        sightings.push(sighting(NEW_HOME, later, later));
+
+        match aggregate(&sightings, true, later, moved_at) {
+            Aggregate::Position(lat, lng, accuracy) => {
+                assert!(distance_to(NEW_HOME, (lat, lng, accuracy)) < 50.0);
+            }
+            other => panic!("expected the new position, got {:?}", other),
+        }
+        // Without the recorded move the larger old cluster would win again
+        let Aggregate::Position(lat, lng, accuracy) = aggregate(&sightings, true, later, 0) else {
+            panic!("expected a position");
+        };
+        assert!(distance_to(HOME, (lat, lng, accuracy)) < 50.0);
+
+        // Once nothing after the move is left, there is no position to keep
+        assert_eq!(
+            aggregate(&history(), true, later, moved_at),
+            Aggregate::Unknown
+        );
+    }
+}
@@ -1,6 +1,16 @@
 // Apple WPS Protobuf messages (manually defined based on GrapheneOS proto)
+use std::fmt;
+
+use bytes::{Buf, BufMut, BytesMut};
 use prost::Message;
 
+// Framing version sent before the request header and expected before the response header
+const FRAME_VERSION: i16 = 1;
+// Version, response code and payload length
+const RESPONSE_HEADER_LEN: usize = 2 + 4 + 4;
+// Response code of a frame carrying a location response
+const RESPONSE_CODE_OK: i32 = 1;
+
 #[derive(Clone, PartialEq, Message)]
 pub struct AlsLocationRequest {
     #[prost(message, repeated, tag = "1")]
@@ -187,16 +197,89 @@ impl AlsLocation {
         let lng = self.longitude as f64 * 0.00000001;
         Some((lat, lng, self.accuracy))
     }
+
+    /// Returns altitude and vertical accuracy in meters, scaled according to the
+    /// `wifi_altitude_scale` of the request. None if Apple has no altitude for the location
+    pub fn to_altitude(&self, scale: WifiAltitudeScale) -> Option<(f64, f64)> {
+        let altitude = self.altitude?;
+        let vertical_accuracy = self.vertical_accuracy.filter(|v| *v > 0)?;
+        let divisor = match scale {
+            WifiAltitudeScale::TenToThe2 => 100.0,
+            WifiAltitudeScale::None | WifiAltitudeScale::Unknown => 1.0,
+        };
+        Some((
+            altitude as f64 / divisor,
+            vertical_accuracy as f64 / divisor,
+        ))
+    }
+}
+
+impl AlsLocationResponse {
+    /// Builds a response for the requested APs and cell towers from locally known
+    /// locations, given in the same order (APs first). Cell towers of radio types
+    /// Apple does not support are left out
+    pub fn from_locations(
+        wifis: &[WifiRequest],
+        cells: &[CellRequest],
+        locations: impl IntoIterator<Item = Option<AlsLocation>>,
+    ) -> Self {
+        let mut locations = locations.into_iter();
+        let mut response = AlsLocationResponse::default();
+
+        for wifi in wifis {
+            response.wireless_aps.push(WirelessAp {
+                mac_id: wifi.bssid.clone(),
+                location: locations.next().flatten(),
+                channel: wifi.channel,
+            });
+        }
+        for cell in cells {
+            let location = locations.next().flatten();
+            match cell.radio_type.as_str() {
+                "gsm" => response.gsm_cell_towers.push(GsmCellTower {
+                    mcc: cell.mcc,
+                    mnc: cell.mnc,
+                    lac_id: cell.lac,
+                    cell_id: cell.cell_id as i32,
+                    location,
+                }),
+                "lte" => response.lte_cell_towers.push(LteCellTower {
+                    mcc: Some(cell.mcc),
+                    mnc: Some(cell.mnc),
+                    tac_id: Some(cell.lac),
+                    cell_id: Some(cell.cell_id as i32),
+                    location,
+                }),
+                "wcdma" => response.scdma_cell_towers.push(ScdmaCellTower {
+                    mcc: cell.mcc,
+                    mnc: cell.mnc,
+                    lac_id: cell.lac,
+                    cell_id: cell.cell_id as i32,
+                    location,
+                }),
+                "nr" => response.nr5g_cell_towers.push(Nr5gCellTower {
+                    mcc: Some(cell.mcc),
+                    mnc: Some(cell.mnc),
+                    tac_id: Some(cell.lac),
+                    cell_id: Some(cell.cell_id),
+                    location,
+                }),
+                _ => {}
+            }
+        }
+
+        response
+    }
 }
 
 impl AlsLocationRequest {
-    pub fn new_wifi_request(bssids: &[String], max_additional: i32) -> Self {
-        let wireless_aps: Vec<WirelessAp> = bssids
+    pub fn new_wifi_request(wifis: &[WifiRequest], max_additional: i32) -> Self {
+        let wireless_aps: Vec<WirelessAp> = wifis
             .iter()
-            .map(|bssid| WirelessAp {
-                mac_id: bssid.clone(),
+            .map(|wifi| WirelessAp {
+                mac_id: wifi.bssid.clone(),
                 location: None,
-                channel: None,
+                channel: wifi.channel,
             })
             .collect();
 
@@ -205,26 +288,17 @@ impl AlsLocationRequest {
             number_of_surrounding_wifis: Some(max_additional.max(1)),
             surrounding_wifi_bands: vec![WifiBand::K2Dot4Ghz as i32, WifiBand::K5Ghz as i32],
             wifi_altitude_scale: Some(WifiAltitudeScale::TenToThe2 as i32),
-            meta: Some(AlsMeta {
-                software_build: Some("macOS15.4/24E248".to_string()),
-                product_id: Some("arm64".to_string()),
-            }),
             ..Default::default()
         }
     }
 
     pub fn new_cell_request(cells: Vec<CellRequest>, max_additional: i32) -> Self {
-        let mut request = AlsLocationRequest {
-            meta: Some(AlsMeta {
-                software_build: Some("macOS15.4/24E248".to_string()),
-                product_id: Some("arm64".to_string()),
-            }),
-            ..Default::default()
-        };
+        let mut request = AlsLocationRequest::default();
 
         let mut gsm_count = 0;
         let mut lte_count = 0;
         let mut wcdma_count = 0;
+        let mut nr_count = 0;
 
         for cell in &cells {
             match cell.radio_type.as_str() {
@@ -233,7 +307,7 @@ impl AlsLocationRequest {
                         mcc: cell.mcc,
                         mnc: cell.mnc,
                         lac_id: cell.lac,
-                        cell_id: cell.cell_id,
+                        cell_id: cell.cell_id as i32,
                         location: None,
                     });
                     gsm_count += 1;
@@ -243,7 +317,7 @@ impl AlsLocationRequest {
                         mcc: Some(cell.mcc),
                         mnc: Some(cell.mnc),
                         tac_id: Some(cell.lac),
-                        cell_id: Some(cell.cell_id),
+                        cell_id: Some(cell.cell_id as i32),
                         location: None,
                     });
                     lte_count += 1;
@@ -253,16 +327,26 @@ impl AlsLocationRequest {
                         mcc: cell.mcc,
                         mnc: cell.mnc,
                         lac_id: cell.lac,
-                        cell_id: cell.cell_id,
+                        cell_id: cell.cell_id as i32,
                         location: None,
                     });
                     wcdma_count += 1;
                 }
+                "nr" => {
+                    request.nr5g_cell_towers.push(Nr5gCellTower {
+                        mcc: Some(cell.mcc),
+                        mnc: Some(cell.mnc),
+                        tac_id: Some(cell.lac),
+                        cell_id: Some(cell.cell_id),
+                        location: None,
+                    });
+                    nr_count += 1;
+                }
                 _ => {}
             }
         }
 
-        let total = gsm_count + lte_count + wcdma_count;
+        let total = gsm_count + lte_count + wcdma_count + nr_count;
         if total > 0 {
             if gsm_count > 0 {
                 request.number_of_surrounding_gsm_cells =
@@ -276,18 +360,22 @@ impl AlsLocationRequest {
                 request.number_of_surrounding_scdma_cells =
                     Some((max_additional * wcdma_count / total).max(1));
             }
+            if nr_count > 0 {
+                request.number_of_surrounding_nr5g_cells =
+                    Some((max_additional * nr_count / total).max(1));
+            }
         }
 
         request
     }
 
     pub fn new_combined_request(
-        bssids: &[String],
+        wifis: &[WifiRequest],
         cells: Vec<CellRequest>,
         max_wifi_additional: i32,
         max_cell_additional: i32,
     ) -> Self {
-        let mut request = Self::new_wifi_request(bssids, max_wifi_additional);
+        let mut request = Self::new_wifi_request(wifis, max_wifi_additional);
         let cell_request = Self::new_cell_request(cells, max_cell_additional);
 
         request.gsm_cell_towers = cell_request.gsm_cell_towers;
@@ -303,10 +391,375 @@ impl AlsLocationRequest {
     }
 }
 
+pub struct WifiRequest {
+    pub bssid: String,
+    pub channel: Option<u32>,
+}
+
+#[derive(Clone)]
 pub struct CellRequest {
     pub radio_type: String,
     pub mcc: i32,
     pub mnc: i32,
     pub lac: i32,
-    pub cell_id: i32,
+    pub cell_id: i64,
+}
+
+/// The Apple device the worker presents itself as. The frame header, the `AlsMeta`
+/// protobuf and the HTTP headers are all derived from it so they never disagree
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientProfile {
+    pub locale: String,
+    pub identifier: String,
+    pub os_version: String,
+    pub build: String,
+    pub product: String,
+    pub user_agent: String,
+}
+
+impl Default for ClientProfile {
+    fn default() -> Self {
+        let os_version = "15.4";
+        ClientProfile {
+            locale: "en_US".to_string(),
+            identifier: "com.apple.locationd".to_string(),
+            os_version: os_version.to_string(),
+            build: "24E248".to_string(),
+            product: "arm64".to_string(),
+            user_agent: Self::user_agent_for(os_version).unwrap_or_default(),
+        }
+    }
+}
+
+/// Darwin kernel version of a macOS version: macOS 11 to 15 run Darwin 20 to 24,
+/// macOS 26 runs Darwin 25. `None` for versions outside those releases
+pub fn darwin_version(os_version: &str) -> Option<String> {
+    let mut parts = os_version.split('.');
+    let major: u32 = parts.next()?.parse().ok()?;
+    let minor: u32 = parts.next().map_or(Some(0), |minor| minor.parse().ok())?;
+    let darwin = match major {
+        11..=15 => major + 9,
+        26.. => major - 1,
+        _ => return None,
+    };
+    Some(format!("{}.{}.0", darwin, minor))
+}
+
+impl ClientProfile {
+    /// locationd's `User-Agent` on `os_version`, ending in the matching Darwin version
+    pub fn user_agent_for(os_version: &str) -> Option<String> {
+        Some(format!(
+            "locationd/2960.0.57 CFNetwork/3826.500.111.1.1 Darwin/{}",
+            darwin_version(os_version)?
+        ))
+    }
+
+    pub fn request_header(&self) -> RequestHeader {
+        RequestHeader {
+            locale: self.locale.clone(),
+            identifier: self.identifier.clone(),
+            version: format!("{}.{}", self.os_version, self.build),
+            request_code: 1,
+        }
+    }
+
+    pub fn meta(&self) -> AlsMeta {
+        AlsMeta {
+            software_build: Some(format!("macOS{}/{}", self.os_version, self.build)),
+            product_id: Some(self.product.clone()),
+        }
+    }
+
+    /// `en_US` becomes `en-US,en;q=0.9`
+    pub fn accept_language(&self) -> String {
+        let tag = self.locale.replace('_', "-");
+        match tag.split_once('-') {
+            Some((language, _)) => format!("{},{};q=0.9", tag, language),
+            None => tag,
+        }
+    }
+}
+
+/// Header fields of a request frame, written before the `AlsLocationRequest` payload
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestHeader {
+    pub locale: String,
+    pub identifier: String,
+    pub version: String,
+    pub request_code: i32,
+}
+
+/// Header fields of a response frame, read before the `AlsLocationResponse` payload
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResponseHeader {
+    pub version: i16,
+    pub response_code: i32,
+    pub payload_length: usize,
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    /// The frame ended before `needed` bytes could be read
+    Truncated {
+        needed: usize,
+        available: usize,
+    },
+    UnsupportedVersion(i16),
+    /// The server answered with a response code other than `RESPONSE_CODE_OK`
+    ResponseCode(i32),
+    /// The declared payload length does not match the bytes that follow the header
+    LengthMismatch {
+        declared: i64,
+        actual: usize,
+    },
+    Decode(prost::DecodeError),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Truncated { needed, available } => write!(
+                f,
+                "frame truncated: needed {} bytes, {} available",
+                needed, available
+            ),
+            FrameError::UnsupportedVersion(version) => {
+                write!(f, "unsupported frame version {}", version)
+            }
+            FrameError::ResponseCode(code) => write!(f, "unexpected response code {}", code),
+            FrameError::LengthMismatch { declared, actual } => write!(
+                f,
+                "declared payload length {} but {} bytes follow the header",
+                declared, actual
+            ),
+            FrameError::Decode(e) => write!(f, "protobuf decode error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+fn put_string(buf: &mut BytesMut, value: &str) {
+    buf.put_i16(value.len() as i16);
+    buf.put_slice(value.as_bytes());
+}
+
+fn ensure_remaining(buf: &[u8], needed: usize) -> Result<(), FrameError> {
+    if buf.remaining() < needed {
+        return Err(FrameError::Truncated {
+            needed,
+            available: buf.remaining(),
+        });
+    }
+    Ok(())
+}
+
+/// Checks that exactly the declared number of payload bytes follow the header
+fn checked_payload(buf: &[u8], declared: i32) -> Result<usize, FrameError> {
+    match usize::try_from(declared) {
+        Ok(length) if length == buf.remaining() => Ok(length),
+        _ => Err(FrameError::LengthMismatch {
+            declared: declared as i64,
+            actual: buf.remaining(),
+        }),
+    }
+}
+
+/// Encodes a request as sent to `/clls/wloc`: version, three length-prefixed
+/// strings, request code, payload length and the protobuf payload
+pub fn encode_request_frame(header: &RequestHeader, request: &AlsLocationRequest) -> Vec<u8> {
+    let proto_bytes = request.encode_to_vec();
+
+    let strings_len = header.locale.len() + header.identifier.len() + header.version.len();
+    let mut buf = BytesMut::with_capacity(2 + 3 * 2 + strings_len + 4 + 4 + proto_bytes.len());
+
+    buf.put_i16(FRAME_VERSION);
+    put_string(&mut buf, &header.locale);
+    put_string(&mut buf, &header.identifier);
+    put_string(&mut buf, &header.version);
+    buf.put_i32(header.request_code);
+    buf.put_i32(proto_bytes.len() as i32);
+    buf.put_slice(&proto_bytes);
+
+    buf.to_vec()
+}
+
+/// Decodes a `/clls/wloc` response: version, response code, payload length and
+/// the protobuf payload. Frames with an error response code are rejected
+pub fn decode_response_frame(
+    mut buf: &[u8],
+) -> Result<(ResponseHeader, AlsLocationResponse), FrameError> {
+    ensure_remaining(buf, RESPONSE_HEADER_LEN)?;
+
+    let version = buf.get_i16();
+    if version != FRAME_VERSION {
+        return Err(FrameError::UnsupportedVersion(version));
+    }
+    let response_code = buf.get_i32();
+    if response_code != RESPONSE_CODE_OK {
+        return Err(FrameError::ResponseCode(response_code));
+    }
+    let declared_length = buf.get_i32();
+    let payload_length = checked_payload(buf, declared_length)?;
+
+    let response = AlsLocationResponse::decode(buf).map_err(FrameError::Decode)?;
+    let header = ResponseHeader {
+        version,
+        response_code,
+        payload_length,
+    };
+
+    Ok((header, response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_string(buf: &mut &[u8]) -> Result<String, FrameError> {
+        ensure_remaining(buf, 2)?;
+        let len = buf.get_i16() as usize;
+        ensure_remaining(buf, len)?;
+        let value = String::from_utf8_lossy(&buf[..len]).into_owned();
+        buf.advance(len);
+        Ok(value)
+    }
+
+    /// Inverse of `encode_request_frame`
+    fn decode_request_frame(
+        mut buf: &[u8],
+    ) -> Result<(RequestHeader, AlsLocationRequest), FrameError> {
+        ensure_remaining(buf, 2)?;
+        let version = buf.get_i16();
+        if version != FRAME_VERSION {
+            return Err(FrameError::UnsupportedVersion(version));
+        }
+        let locale = get_string(&mut buf)?;
+        let identifier = get_string(&mut buf)?;
+        let os_version = get_string(&mut buf)?;
+        ensure_remaining(buf, 8)?;
+        let request_code = buf.get_i32();
+        let declared_length = buf.get_i32();
+        checked_payload(buf, declared_length)?;
+
+        let request = AlsLocationRequest::decode(buf).map_err(FrameError::Decode)?;
+        let header = RequestHeader {
+            locale,
+            identifier,
+            version: os_version,
+            request_code,
+        };
+
+        Ok((header, request))
+    }
+
+    /// Inverse of `decode_response_frame`
+    fn encode_response_frame(response_code: i32, response: &AlsLocationResponse) -> Vec<u8> {
+        let proto_bytes = response.encode_to_vec();
+        let mut buf = BytesMut::with_capacity(RESPONSE_HEADER_LEN + proto_bytes.len());
+        buf.put_i16(FRAME_VERSION);
+        buf.put_i32(response_code);
+        buf.put_i32(proto_bytes.len() as i32);
+        buf.put_slice(&proto_bytes);
+        buf.to_vec()
+    }
+
+    fn wifi_requests() -> Vec<WifiRequest> {
+        ["00:11:22:33:44:55", "66:77:88:99:aa:bb"]
+            .iter()
+            .map(|bssid| WifiRequest {
+                bssid: bssid.to_string(),
+                channel: Some(6),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn request_frame_round_trip() {
+        let header = ClientProfile {
+            locale: "de_DE".to_string(),
+            ..Default::default()
+        }
+        .request_header();
+        let request = AlsLocationRequest::new_wifi_request(&wifi_requests(), 100);
+
+        let frame = encode_request_frame(&header, &request);
+        let (decoded_header, decoded_request) = decode_request_frame(&frame).unwrap();
+
+        assert_eq!(decoded_header, header);
+        assert_eq!(decoded_request, request);
+    }
+
+    #[test]
+    fn user_agent_follows_os_version() {
+        assert_eq!(
+            ClientProfile::default().user_agent,
+            "locationd/2960.0.57 CFNetwork/3826.500.111.1.1 Darwin/24.4.0"
+        );
+        assert_eq!(darwin_version("14").as_deref(), Some("23.0.0"));
+        assert_eq!(darwin_version("15.6.1").as_deref(), Some("24.6.0"));
+        assert_eq!(darwin_version("26.1").as_deref(), Some("25.1.0"));
+        assert_eq!(darwin_version("10.15"), None);
+        assert_eq!(darwin_version("16.0"), None);
+    }
+
+    #[test]
+    fn response_frame_round_trip() {
+        let response = AlsLocationResponse {
+            wireless_aps: vec![WirelessAp {
+                mac_id: "0:11:22:33:44:55".to_string(),
+                location: Some(AlsLocation {
+                    latitude: 4_590_000_000,
+                    longitude: 918_000_000,
+                    accuracy: 25,
+                    ..Default::default()
+                }),
+                channel: Some(6),
+            }],
+            ..Default::default()
+        };
+
+        let frame = encode_response_frame(RESPONSE_CODE_OK, &response);
+        let (header, decoded) = decode_response_frame(&frame).unwrap();
+
+        assert_eq!(header.version, FRAME_VERSION);
+        assert_eq!(header.response_code, RESPONSE_CODE_OK);
+        assert_eq!(header.payload_length, frame.len() - RESPONSE_HEADER_LEN);
+        assert_eq!(decoded, response);
+    }
+
+    #[test]
+    fn response_frame_errors() {
+        let frame = encode_response_frame(RESPONSE_CODE_OK, &AlsLocationResponse::default());
+        assert!(matches!(
+            decode_response_frame(&frame[..4]),
+            Err(FrameError::Truncated {
+                needed: 10,
+                available: 4
+            })
+        ));
+
+        let mut wrong_version = frame.clone();
+        wrong_version[1] = 2;
+        assert!(matches!(
+            decode_response_frame(&wrong_version),
+            Err(FrameError::UnsupportedVersion(2))
+        ));
+
+        let error_code = encode_response_frame(2, &AlsLocationResponse::default());
+        assert!(matches!(
+            decode_response_frame(&error_code),
+            Err(FrameError::ResponseCode(2))
+        ));
+
+        let response = AlsLocationResponse {
+            wireless_aps: vec![WirelessAp::default()],
+            ..Default::default()
+        };
+        let frame = encode_response_frame(RESPONSE_CODE_OK, &response);
+        assert!(matches!(
+            decode_response_frame(&frame[..frame.len() - 1]),
+            Err(FrameError::LengthMismatch { .. })
+        ));
+    }
 }
@@ -0,0 +1,461 @@
+// Location providers the worker can resolve network data with
+use std::fmt;
+use std::time::Duration;
+
+use futures_util::future::{select, Either};
+use futures_util::pin_mut;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use worker::async_trait::async_trait;
+use worker::*;
+
+use crate::apple_wps::{
+    self, AlsLocationRequest, AlsLocationResponse, CellRequest, ClientProfile, WifiAltitudeScale,
+    WifiRequest,
+};
+use crate::breaker::CircuitBreaker;
+use crate::config::{self, HedgeConfig, MlsEndpoint, RetryPolicy, UpstreamConfig, UpstreamMode};
+use crate::estimate::estimate_position;
+use crate::local_dataset::LocalDataset;
+use crate::position_cache::{PositionCache, LOCAL_ALTITUDE_SCALE};
+use crate::{MlsRequest, MlsResponse};
+
+/// Why a backend call failed
+#[derive(Debug)]
+pub enum BackendError {
+    /// Network errors, timeouts, 429 and 5xx responses, worth retrying
+    Transient(String),
+    /// Rejected requests and unreadable responses, retrying will not help
+    Permanent(String),
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendError::Transient(e) => write!(f, "transient: {}", e),
+            BackendError::Permanent(e) => write!(f, "permanent: {}", e),
+        }
+    }
+}
+
+impl From<Error> for BackendError {
+    // Errors from the fetch itself are network failures
+    fn from(e: Error) -> Self {
+        BackendError::Transient(e.to_string())
+    }
+}
+
+impl BackendError {
+    fn from_status(status: u16) -> Self {
+        match status {
+            429 | 500..=599 => BackendError::Transient(format!("HTTP {}", status)),
+            _ => BackendError::Permanent(format!("HTTP {}", status)),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+pub trait LocationBackend {
+    fn name(&self) -> &str;
+
+    /// Resolves the request's WiFi and cell data. `Ok(None)` means the backend
+    /// answered but could not locate it
+    async fn locate(
+        &self,
+        request: &MlsRequest,
+    ) -> std::result::Result<Option<MlsResponse>, BackendError>;
+}
+
+/// Locates the request from the local dataset or the position cache when they know
+/// its networks, otherwise with the configured backends. A dataset fix that could not
+/// use the request's WiFi data is only returned when no backend locates the request.
+/// In the default failover mode the backends are tried in order until one locates it;
+/// in hedged mode the first two are queried concurrently and the rest are only tried
+/// if neither does. Each call is
+/// bounded by a timeout and retried with exponential backoff on transient failures;
+/// backends whose circuit breaker is open are skipped. Cache writes are left to `ctx`
+/// so they do not delay the response
+pub async fn locate(env: &Env, request: &MlsRequest, ctx: &Context) -> Option<MlsResponse> {
+    let mut partial = None;
+    if let Some(dataset) = LocalDataset::new(env) {
+        if let Some(response) = dataset.locate(request).await {
+            // A cell fix while the dataset lacks the APs is a miss for the WiFi data
+            if !request.has_wifi_data() || response.aps_used.is_some() {
+                return Some(response);
+            }
+            partial = Some(response);
+        }
+    }
+    if let Some(response) = locate_cached(env, request).await {
+        return Some(response);
+    }
+
+    let policy = config::retry_policy(env);
+    let breaker = CircuitBreaker::new(env);
+    let mut backends = configured_backends(env, request, ctx).await;
+
+    if let Some(hedge) = config::hedge_config(env) {
+        let rest = backends.split_off(backends.len().min(2));
+        let response = locate_hedged(&backends, request, &policy, &breaker, &hedge).await;
+        if response.is_some() {
+            return response;
+        }
+        backends = rest;
+    }
+
+    for backend in &backends {
+        if let Some(response) = locate_guarded(backend.as_ref(), request, &policy, &breaker).await {
+            return Some(response);
+        }
+    }
+
+    partial
+}
+
+/// Answers from the positions cached from earlier Apple WPS responses when every AP
+/// and cell tower in the request is cached
+async fn locate_cached(env: &Env, request: &MlsRequest) -> Option<MlsResponse> {
+    let cache = PositionCache::new(env)?;
+    let wifis = request.get_wifis();
+    let cells = request.get_cells(&request.radio_type);
+    let cached_response = cache.lookup(&wifis, &cells).await?;
+
+    estimate_position(&cached_response, request, LOCAL_ALTITUDE_SCALE).map(|response| MlsResponse {
+        backend: Some("cache".to_string()),
+        ..response
+    })
+}
+
+/// Queries `backends` concurrently. Returns the first fix within `accept_accuracy`,
+/// otherwise the most accurate fix received by the deadline, or after it the first
+/// one to arrive
+async fn locate_hedged(
+    backends: &[Box<dyn LocationBackend + '_>],
+    request: &MlsRequest,
+    policy: &RetryPolicy,
+    breaker: &CircuitBreaker,
+    hedge: &HedgeConfig,
+) -> Option<MlsResponse> {
+    let mut pending: FuturesUnordered<_> = backends
+        .iter()
+        .map(|backend| locate_guarded(backend.as_ref(), request, policy, breaker))
+        .collect();
+    let deadline = Delay::from(hedge.deadline);
+    pin_mut!(deadline);
+    let mut best: Option<MlsResponse> = None;
+    let mut deadline_passed = false;
+
+    loop {
+        let next = if deadline_passed {
+            pending.next().await
+        } else {
+            match select(pending.next(), deadline.as_mut()).await {
+                Either::Left((next, _)) => next,
+                Either::Right(_) => {
+                    deadline_passed = true;
+                    if best.is_some() {
+                        break;
+                    }
+                    continue;
+                }
+            }
+        };
+
+        match next {
+            Some(Some(response)) => {
+                if response.accuracy <= hedge.accept_accuracy || deadline_passed {
+                    return Some(response);
+                }
+                if best.as_ref().is_none_or(|b| response.accuracy < b.accuracy) {
+                    best = Some(response);
+                }
+            }
+            Some(None) => {}
+            // Every backend has answered
+            None => break,
+        }
+    }
+
+    best
+}
+
+/// Calls one backend unless its circuit breaker is open, recording the outcome and
+/// tagging the fix with the backend's name
+async fn locate_guarded(
+    backend: &dyn LocationBackend,
+    request: &MlsRequest,
+    policy: &RetryPolicy,
+    breaker: &CircuitBreaker,
+) -> Option<MlsResponse> {
+    let name = backend.name();
+    let state = breaker.state(name).await;
+    if state.is_open() {
+        console_warn!("Skipping {} backend, circuit breaker is open", name);
+        return None;
+    }
+
+    match locate_with_retries(backend, request, policy).await {
+        Ok(response) => {
+            breaker.record(name, state, true).await;
+            response.map(|response| MlsResponse {
+                backend: Some(name.to_string()),
+                ..response
+            })
+        }
+        Err(e) => {
+            console_warn!("{} backend failed: {}", name, e);
+            breaker.record(name, state, false).await;
+            None
+        }
+    }
+}
+
+async fn locate_with_retries(
+    backend: &dyn LocationBackend,
+    request: &MlsRequest,
+    policy: &RetryPolicy,
+) -> std::result::Result<Option<MlsResponse>, BackendError> {
+    let mut attempt = 0;
+    loop {
+        match with_timeout(backend.locate(request), policy.timeout).await {
+            Err(BackendError::Transient(e)) if attempt < policy.retries => {
+                console_warn!(
+                    "{} backend attempt {} failed: {}",
+                    backend.name(),
+                    attempt + 1,
+                    e
+                );
+                Delay::from(policy.backoff * 2u32.pow(attempt)).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+async fn with_timeout<F>(
+    future: F,
+    timeout: Duration,
+) -> std::result::Result<Option<MlsResponse>, BackendError>
+where
+    F: std::future::Future<Output = std::result::Result<Option<MlsResponse>, BackendError>>,
+{
+    let delay = Delay::from(timeout);
+    pin_mut!(future, delay);
+    match select(future, delay).await {
+        Either::Left((result, _)) => result,
+        Either::Right(_) => Err(BackendError::Transient(format!(
+            "timed out after {} ms",
+            timeout.as_millis()
+        ))),
+    }
+}
+
+/// Builds the backends listed in `LOCATION_BACKENDS` for `request`, in order.
+/// Misconfigured backends are skipped with a warning
+pub async fn configured_backends<'a>(
+    env: &Env,
+    request: &MlsRequest,
+    ctx: &'a Context,
+) -> Vec<Box<dyn LocationBackend + 'a>> {
+    let mut backends: Vec<Box<dyn LocationBackend + 'a>> = Vec::new();
+
+    for name in config::backend_order(env) {
+        if name == "apple" {
+            backends.push(Box::new(AppleWpsBackend::new(env, request, ctx).await));
+            continue;
+        }
+
+        match config::mls_endpoint(env, &name) {
+            Some(endpoint) => backends.push(Box::new(MlsBackend { name, endpoint })),
+            None => console_warn!("Skipping unknown or unconfigured backend {:?}", name),
+        }
+    }
+
+    backends
+}
+
+/// Apple's WiFi Positioning System, through a proxy or directly
+pub struct AppleWpsBackend<'a> {
+    profile: ClientProfile,
+    upstream: UpstreamConfig,
+    cache: Option<PositionCache>,
+    ctx: &'a Context,
+    /// The request's networks, less those Apple recently could not locate
+    wifis: Vec<WifiRequest>,
+    cells: Vec<CellRequest>,
+}
+
+impl<'a> AppleWpsBackend<'a> {
+    /// Looks up the unknown networks once, so the KV reads count neither against the
+    /// backend timeout nor once per retry
+    async fn new(env: &Env, request: &MlsRequest, ctx: &'a Context) -> Self {
+        let cache = PositionCache::new(env);
+        let mut wifis = request.get_wifis();
+        let mut cells = request.get_cells(&request.radio_type);
+        if let Some(cache) = &cache {
+            cache.strip_unknown(&mut wifis, &mut cells).await;
+        }
+
+        AppleWpsBackend {
+            profile: config::apple_client_profile(env),
+            upstream: config::apple_upstream(env),
+            cache,
+            ctx,
+            wifis,
+            cells,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl LocationBackend for AppleWpsBackend<'_> {
+    fn name(&self) -> &str {
+        "apple"
+    }
+
+    async fn locate(
+        &self,
+        request: &MlsRequest,
+    ) -> std::result::Result<Option<MlsResponse>, BackendError> {
+        // Networks Apple recently could not locate are not asked about again
+        if self.wifis.is_empty() && self.cells.is_empty() {
+            return Ok(None);
+        }
+        let (wifis, cells) = (&self.wifis, self.cells.clone());
+
+        let mut apple_request = if !wifis.is_empty() && !cells.is_empty() {
+            AlsLocationRequest::new_combined_request(wifis, cells, 100, 25)
+        } else if !wifis.is_empty() {
+            AlsLocationRequest::new_wifi_request(wifis, 100)
+        } else {
+            AlsLocationRequest::new_cell_request(cells, 25)
+        };
+        apple_request.meta = Some(self.profile.meta());
+
+        let apple_response = query_apple_wps(&apple_request, &self.profile, &self.upstream).await?;
+
+        let altitude_scale =
+            WifiAltitudeScale::from(apple_request.wifi_altitude_scale.unwrap_or_default());
+        let response = estimate_position(&apple_response, request, altitude_scale);
+
+        // Cached after the response is sent, slow KV writes must not time out the call
+        if let Some(cache) = self.cache.clone() {
+            self.ctx.wait_until(async move {
+                cache.store(&apple_response, altitude_scale).await;
+            });
+        }
+
+        Ok(response)
+    }
+}
+
+async fn query_apple_wps(
+    request: &AlsLocationRequest,
+    profile: &ClientProfile,
+    upstream: &UpstreamConfig,
+) -> std::result::Result<AlsLocationResponse, BackendError> {
+    let body = apple_wps::encode_request_frame(&profile.request_header(), request);
+
+    let body_array = js_sys::Uint8Array::from(body.as_slice());
+
+    let new_headers = web_sys::Headers::new().map_err(|e| Error::JsError(format!("{:?}", e)))?;
+    new_headers
+        .set("Accept", "*/*")
+        .map_err(|e| Error::JsError(format!("{:?}", e)))?;
+    new_headers
+        .set("Accept-Language", &profile.accept_language())
+        .map_err(|e| Error::JsError(format!("{:?}", e)))?;
+    new_headers
+        .set("Content-Type", "application/x-www-form-urlencoded")
+        .map_err(|e| Error::JsError(format!("{:?}", e)))?;
+    new_headers
+        .set("User-Agent", &profile.user_agent)
+        .map_err(|e| Error::JsError(format!("{:?}", e)))?;
+    if upstream.mode == UpstreamMode::Direct {
+        // The proxy adds these itself, Apple expects them from locationd
+        new_headers
+            .set("Accept-Charset", "utf-8")
+            .map_err(|e| Error::JsError(format!("{:?}", e)))?;
+        new_headers
+            .set("Accept-Encoding", "gzip, deflate")
+            .map_err(|e| Error::JsError(format!("{:?}", e)))?;
+    }
+
+    let new_init = web_sys::RequestInit::new();
+    new_init.set_method("POST");
+    new_init.set_body(&body_array);
+    new_init.set_headers(&new_headers);
+
+    let web_req = web_sys::Request::new_with_str_and_init(&upstream.url, &new_init)
+        .map_err(|e| Error::JsError(format!("{:?}", e)))?;
+
+    let req = Request::from(web_req);
+    let mut response = Fetch::Request(req).send().await?;
+
+    if response.status_code() != 200 {
+        return Err(BackendError::from_status(response.status_code()));
+    }
+
+    let response_bytes = response.bytes().await?;
+    let (_, als_response) = apple_wps::decode_response_frame(&response_bytes)
+        .map_err(|e| BackendError::Permanent(format!("Apple WPS response: {}", e)))?;
+
+    Ok(als_response)
+}
+
+/// Any service speaking the MLS/Google geolocate JSON API: BeaconDB, a self-hosted
+/// Ichnaea or the Google Geolocation API
+pub struct MlsBackend {
+    name: String,
+    endpoint: MlsEndpoint,
+}
+
+#[async_trait(?Send)]
+impl LocationBackend for MlsBackend {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn locate(
+        &self,
+        request: &MlsRequest,
+    ) -> std::result::Result<Option<MlsResponse>, BackendError> {
+        let mut url =
+            Url::parse(&self.endpoint.url).map_err(|e| BackendError::Permanent(e.to_string()))?;
+        if let Some(key) = &self.endpoint.api_key {
+            url.query_pairs_mut().append_pair("key", key);
+        }
+
+        // The upstream would only see Cloudflare's address, IP fallback happens here
+        let mut body =
+            serde_json::to_value(request).map_err(|e| BackendError::Permanent(e.to_string()))?;
+        body["considerIp"] = false.into();
+
+        let headers = Headers::new();
+        headers.set("Content-Type", "application/json")?;
+        let mut init = RequestInit::new();
+        init.with_method(Method::Post)
+            .with_headers(headers)
+            .with_body(Some(body.to_string().into()));
+
+        let mut response = Fetch::Request(Request::new_with_init(url.as_str(), &init)?)
+            .send()
+            .await?;
+        match response.status_code() {
+            200 => {}
+            // The MLS API answers 404 when it has no location for the request
+            404 => return Ok(None),
+            status => return Err(BackendError::from_status(status)),
+        }
+
+        let location: MlsResponse = response
+            .json()
+            .await
+            .map_err(|e| BackendError::Permanent(e.to_string()))?;
+        if location.fallback.as_deref() == Some("ipf") {
+            return Ok(None);
+        }
+
+        Ok(Some(location))
+    }
+}
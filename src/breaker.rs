@@ -0,0 +1,105 @@
+// Circuit breaker per backend, shared across isolates through Workers KV
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use worker::{console_warn, Date, Env, KvStore};
+
+use crate::config::{self, BreakerConfig};
+
+const KV_BINDING: &str = "CIRCUIT_BREAKER";
+// KV rejects expiration TTLs below one minute
+const KV_MIN_TTL_SECS: u64 = 60;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct BreakerState {
+    failures: u32,
+    /// Epoch milliseconds until which the backend is skipped
+    open_until: u64,
+}
+
+impl BreakerState {
+    pub fn is_open(&self) -> bool {
+        self.open_until > Date::now().as_millis()
+    }
+}
+
+thread_local! {
+    // Used when no KV namespace is bound, only shared by requests on the same isolate
+    static LOCAL_STATE: RefCell<HashMap<String, BreakerState>> = RefCell::new(HashMap::new());
+}
+
+pub struct CircuitBreaker {
+    kv: Option<KvStore>,
+    config: BreakerConfig,
+}
+
+impl CircuitBreaker {
+    pub fn new(env: &Env) -> Self {
+        CircuitBreaker {
+            kv: env.kv(KV_BINDING).ok(),
+            config: config::breaker_config(env),
+        }
+    }
+
+    fn key(name: &str) -> String {
+        format!("breaker:{}", name)
+    }
+
+    /// Current state for `name`. KV errors fail open so a KV outage never blocks lookups
+    pub async fn state(&self, name: &str) -> BreakerState {
+        let Some(kv) = &self.kv else {
+            return LOCAL_STATE.with(|s| s.borrow().get(name).copied().unwrap_or_default());
+        };
+
+        match kv.get(&Self::key(name)).json::<BreakerState>().await {
+            Ok(state) => state.unwrap_or_default(),
+            Err(e) => {
+                console_warn!("Circuit breaker state for {} unavailable: {}", name, e);
+                BreakerState::default()
+            }
+        }
+    }
+
+    /// Records the outcome of a call made with `state`. Enough consecutive failures open
+    /// the breaker for the cooldown; the first call after it is a trial that either
+    /// closes it again or reopens it
+    pub async fn record(&self, name: &str, state: BreakerState, success: bool) {
+        let new_state = if success {
+            BreakerState::default()
+        } else {
+            let failures = state.failures.saturating_add(1);
+            let open_until = if failures >= self.config.failure_threshold {
+                Date::now().as_millis() + self.config.cooldown.as_millis() as u64
+            } else {
+                state.open_until
+            };
+            BreakerState {
+                failures,
+                open_until,
+            }
+        };
+        if new_state == state {
+            return;
+        }
+
+        let Some(kv) = &self.kv else {
+            LOCAL_STATE.with(|s| s.borrow_mut().insert(name.to_string(), new_state));
+            return;
+        };
+
+        let key = Self::key(name);
+        let result = if new_state == BreakerState::default() {
+            kv.delete(&key).await
+        } else {
+            let ttl = (self.config.cooldown.as_secs() * 2).max(KV_MIN_TTL_SECS);
+            match kv.put(&key, new_state) {
+                Ok(put) => put.expiration_ttl(ttl).execute().await,
+                Err(e) => Err(e),
+            }
+        };
+        if let Err(e) = result {
+            console_warn!("Failed to store circuit breaker state for {}: {}", name, e);
+        }
+    }
+}
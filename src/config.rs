@@ -0,0 +1,319 @@
+// Worker configuration read from `wrangler.toml` vars and secrets
+use std::fmt::Display;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::time::Duration;
+
+use worker::{console_warn, Env, Url};
+
+use crate::apple_wps::{self, ClientProfile};
+
+/// Reads a var or secret, `None` if it is unset or empty
+fn env_string(env: &Env, name: &str) -> Option<String> {
+    env.var(name)
+        .map(|v| v.to_string())
+        .or_else(|_| env.secret(name).map(|s| s.to_string()))
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// Reads a var or secret, falling back to `default` when it is unset or fails `is_valid`
+fn validated(env: &Env, name: &str, default: String, is_valid: impl Fn(&str) -> bool) -> String {
+    match env_string(env, name) {
+        Some(value) if is_valid(&value) => value,
+        Some(value) => {
+            console_warn!("Ignoring invalid {}={:?}, using {:?}", name, value, default);
+            default
+        }
+        None => default,
+    }
+}
+
+/// Reads a numeric var, falling back to `default` when it is unset, unparsable or
+/// outside `range`
+fn number<T>(env: &Env, name: &str, default: T, range: RangeInclusive<T>) -> T
+where
+    T: FromStr + PartialOrd + Display,
+{
+    let Some(value) = env_string(env, name) else {
+        return default;
+    };
+    match value.parse::<T>() {
+        Ok(number) if range.contains(&number) => number,
+        _ => {
+            console_warn!("Ignoring invalid {}={:?}, using {}", name, value, default);
+            default
+        }
+    }
+}
+
+/// `en_US`, `de_DE`, `fr`
+fn is_valid_locale(value: &str) -> bool {
+    let (language, region) = match value.split_once('_') {
+        Some((language, region)) => (language, Some(region)),
+        None => (value, None),
+    };
+    (2..=3).contains(&language.len())
+        && language.chars().all(|c| c.is_ascii_lowercase())
+        && region.is_none_or(|r| r.len() == 2 && r.chars().all(|c| c.is_ascii_uppercase()))
+}
+
+/// `com.apple.locationd`
+fn is_valid_identifier(value: &str) -> bool {
+    value.split('.').count() >= 2
+        && value.split('.').all(|part| {
+            !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// `15.4`, a macOS release whose Darwin version is known
+fn is_valid_os_version(value: &str) -> bool {
+    value
+        .split('.')
+        .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()))
+        && apple_wps::darwin_version(value).is_some()
+}
+
+/// `24E248`, `arm64`
+fn is_valid_token(value: &str) -> bool {
+    value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ',')
+}
+
+/// Printable ASCII, so it can be sent as an HTTP header value
+fn is_valid_user_agent(value: &str) -> bool {
+    value.chars().all(|c| c.is_ascii_graphic() || c == ' ')
+}
+
+/// The Apple client identity, configurable so a rejected build can be replaced
+/// without redeploying
+pub fn apple_client_profile(env: &Env) -> ClientProfile {
+    let default = ClientProfile::default();
+    let os_version = validated(
+        env,
+        "APPLE_OS_VERSION",
+        default.os_version,
+        is_valid_os_version,
+    );
+    // A User-Agent from another Darwin release than the OS version would give the
+    // client away, so it is derived from the OS version unless one matching it is set
+    let darwin = format!(
+        "Darwin/{}",
+        apple_wps::darwin_version(&os_version).unwrap_or_default()
+    );
+    let user_agent = validated(
+        env,
+        "APPLE_USER_AGENT",
+        ClientProfile::user_agent_for(&os_version).unwrap_or(default.user_agent),
+        |value| is_valid_user_agent(value) && value.split(' ').any(|token| token == darwin),
+    );
+
+    ClientProfile {
+        locale: validated(env, "APPLE_LOCALE", default.locale, is_valid_locale),
+        identifier: validated(
+            env,
+            "APPLE_IDENTIFIER",
+            default.identifier,
+            is_valid_identifier,
+        ),
+        os_version,
+        build: validated(env, "APPLE_BUILD", default.build, is_valid_token),
+        product: validated(env, "APPLE_PRODUCT", default.product, is_valid_token),
+        user_agent,
+    }
+}
+
+const GRAPHENEOS_PROXY_URL: &str = "https://gs-loc.apple.grapheneos.org/clls/wloc";
+const APPLE_WPS_URL: &str = "https://gs-loc.apple.com/clls/wloc";
+
+/// How the upstream URL is reached
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpstreamMode {
+    /// A privacy proxy in front of Apple WPS, such as the GrapheneOS one
+    Proxy,
+    /// Apple's `gs-loc.apple.com` endpoint itself
+    Direct,
+}
+
+#[derive(Clone, Debug)]
+pub struct UpstreamConfig {
+    pub url: String,
+    pub mode: UpstreamMode,
+}
+
+/// `APPLE_WPS_MODE` is `proxy` (default) or `direct`; `APPLE_WPS_URL` defaults to the
+/// GrapheneOS proxy or to `gs-loc.apple.com` accordingly and must be an https URL
+pub fn apple_upstream(env: &Env) -> UpstreamConfig {
+    let mode = match env_string(env, "APPLE_WPS_MODE").as_deref() {
+        None | Some("proxy") => UpstreamMode::Proxy,
+        Some("direct") => UpstreamMode::Direct,
+        Some(other) => {
+            console_warn!(
+                "Ignoring invalid APPLE_WPS_MODE={:?}, using \"proxy\"",
+                other
+            );
+            UpstreamMode::Proxy
+        }
+    };
+    let default_url = match mode {
+        UpstreamMode::Proxy => GRAPHENEOS_PROXY_URL,
+        UpstreamMode::Direct => APPLE_WPS_URL,
+    };
+
+    UpstreamConfig {
+        url: validated(
+            env,
+            "APPLE_WPS_URL",
+            default_url.to_string(),
+            is_valid_https_url,
+        ),
+        mode,
+    }
+}
+
+fn is_valid_https_url(value: &str) -> bool {
+    Url::parse(value).is_ok_and(|url| url.scheme() == "https" && url.host().is_some())
+}
+
+/// Backend names from `LOCATION_BACKENDS`, comma separated and in priority order.
+/// `none` disables all of them, for deployments answering from a local dataset only
+pub fn backend_order(env: &Env) -> Vec<String> {
+    env_string(env, "LOCATION_BACKENDS")
+        .unwrap_or_else(|| "apple".to_string())
+        .split(',')
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty() && name != "none")
+        .collect()
+}
+
+const BEACONDB_URL: &str = "https://api.beacondb.net/v1/geolocate";
+const GOOGLE_GEOLOCATION_URL: &str = "https://www.googleapis.com/geolocation/v1/geolocate";
+
+/// An MLS-compatible geolocate endpoint and its API key
+#[derive(Clone, Debug)]
+pub struct MlsEndpoint {
+    pub url: String,
+    pub api_key: Option<String>,
+}
+
+/// Endpoint settings for the MLS-compatible backends. `None` if `name` is not one of
+/// them or a required setting (`ICHNAEA_URL`, `GOOGLE_API_KEY`) is missing
+pub fn mls_endpoint(env: &Env, name: &str) -> Option<MlsEndpoint> {
+    let url = |var: &str, default: Option<&str>| {
+        let default = default.unwrap_or_default().to_string();
+        Some(validated(env, var, default, is_valid_https_url)).filter(|url| !url.is_empty())
+    };
+
+    match name {
+        "beacondb" => Some(MlsEndpoint {
+            url: url("BEACONDB_URL", Some(BEACONDB_URL))?,
+            api_key: None,
+        }),
+        "ichnaea" => Some(MlsEndpoint {
+            url: url("ICHNAEA_URL", None)?,
+            api_key: env_string(env, "ICHNAEA_API_KEY"),
+        }),
+        "google" => Some(MlsEndpoint {
+            url: url("GOOGLE_GEOLOCATION_URL", Some(GOOGLE_GEOLOCATION_URL))?,
+            api_key: Some(env_string(env, "GOOGLE_API_KEY")?),
+        }),
+        _ => None,
+    }
+}
+
+/// How each backend call is bounded and retried
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub timeout: Duration,
+    /// Extra attempts after a transient failure
+    pub retries: u32,
+    /// Delay before the first retry, doubled for each further one
+    pub backoff: Duration,
+}
+
+pub fn retry_policy(env: &Env) -> RetryPolicy {
+    RetryPolicy {
+        timeout: Duration::from_millis(number(env, "BACKEND_TIMEOUT_MS", 3000, 100..=30_000)),
+        retries: number(env, "BACKEND_RETRIES", 2, 0..=5),
+        backoff: Duration::from_millis(number(env, "BACKEND_BACKOFF_MS", 100, 0..=5000)),
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BreakerConfig {
+    /// Consecutive failed calls that open the breaker
+    pub failure_threshold: u32,
+    /// How long an open breaker skips the backend
+    pub cooldown: Duration,
+}
+
+pub fn breaker_config(env: &Env) -> BreakerConfig {
+    BreakerConfig {
+        failure_threshold: number(env, "BREAKER_FAILURE_THRESHOLD", 5, 1..=100),
+        cooldown: Duration::from_secs(number(env, "BREAKER_COOLDOWN_SECS", 60, 1..=3600)),
+    }
+}
+
+/// Settings for querying the first two backends concurrently
+#[derive(Clone, Debug)]
+pub struct HedgeConfig {
+    /// How long to wait for a fix within `accept_accuracy` before settling for the
+    /// most accurate one received
+    pub deadline: Duration,
+    /// Accuracy in meters good enough to answer with immediately
+    pub accept_accuracy: f64,
+}
+
+/// `Some` when `BACKEND_STRATEGY` is `hedged`, `None` for the default `failover`
+pub fn hedge_config(env: &Env) -> Option<HedgeConfig> {
+    match env_string(env, "BACKEND_STRATEGY").as_deref() {
+        None | Some("failover") => return None,
+        Some("hedged") => {}
+        Some(other) => {
+            console_warn!(
+                "Ignoring invalid BACKEND_STRATEGY={:?}, using \"failover\"",
+                other
+            );
+            return None;
+        }
+    }
+
+    Some(HedgeConfig {
+        deadline: Duration::from_millis(number(env, "HEDGE_DEADLINE_MS", 1000, 0..=30_000)),
+        accept_accuracy: number(env, "HEDGE_ACCEPT_ACCURACY", 100.0, 0.0..=100_000.0),
+    })
+}
+
+/// How long AP and cell positions stay in the edge cache, one week by default
+pub fn position_cache_ttl(env: &Env) -> Duration {
+    Duration::from_secs(number(
+        env,
+        "POSITION_CACHE_TTL_SECS",
+        604_800,
+        60..=2_592_000,
+    ))
+}
+
+/// How long whole geolocate responses are cached, 0 disables the response cache
+pub fn response_cache_ttl(env: &Env) -> Duration {
+    Duration::from_secs(number(env, "RESPONSE_CACHE_TTL_SECS", 300, 0..=86_400))
+}
+
+/// How long APs and cell towers Apple does not know are left out of requests, one
+/// day by default
+pub fn unknown_cache_ttl(env: &Env) -> Duration {
+    Duration::from_secs(number(
+        env,
+        "UNKNOWN_CACHE_TTL_SECS",
+        86_400,
+        60..=2_592_000,
+    ))
+}
+
+/// Distinct APs a request needs before its WiFi data is used, at least 2 so a
+/// single router cannot be looked up
+pub fn min_distinct_aps(env: &Env) -> usize {
+    number(env, "MIN_DISTINCT_APS", 2, 2..=20)
+}
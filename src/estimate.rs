@@ -0,0 +1,818 @@
+// Position estimation from Apple WPS results and the client's observations
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::apple_wps::{AlsLocationResponse, WifiAltitudeScale};
+use crate::mac::MacAddress;
+use crate::{CellTower, Location, MlsRequest, MlsResponse, WifiAccessPoint};
+
+// Log-distance path loss model parameters for indoor WiFi
+const WIFI_RSSI_AT_ONE_METER: f64 = -40.0;
+const WIFI_PATH_LOSS_EXPONENT: f64 = 3.0;
+const WIFI_MAX_DISTANCE: f64 = 500.0;
+// Same default as Ichnaea when the client does not report a signal
+const WIFI_DEFAULT_SIGNAL: i32 = -80;
+// APs further apart than this cannot have been seen in the same scan
+const WIFI_CLUSTER_DISTANCE: f64 = 500.0;
+// Virtual APs of one router get consecutive BSSIDs, one per band or SSID
+const NEAR_IDENTICAL_BSSID_RANGE: u64 = 16;
+// Sightings of one cell further apart than this cannot be of the same tower
+const CELL_CLUSTER_DISTANCE: f64 = 50_000.0;
+// Assumed GPS accuracy of sightings that do not report one
+const SIGHTING_DEFAULT_ACCURACY: f64 = 20.0;
+
+// The RMS radial error of a 2D normal distribution holds 63% of fixes, the
+// 68% radius is sqrt(-2 ln 0.32) / sqrt(2) times larger
+const CONFIDENCE_SCALE: f64 = 1.07;
+// Floors for the reported accuracy radius
+const WIFI_MIN_ACCURACY: f64 = 10.0;
+const CELL_MIN_ACCURACY: f64 = 100.0;
+
+const EARTH_RADIUS: f64 = 6_371_000.0;
+
+/// Great-circle distance in meters between two coordinates
+pub fn haversine_distance(lat1: f64, lng1: f64, lat2: f64, lng2: f64) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let d_lat = lat2 - lat1;
+    let d_lng = (lng2 - lng1).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lng / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS * a.sqrt().asin()
+}
+
+/// Weighted centroid of `(lat, lng, sigma, weight)` positions and its accuracy radius.
+///
+/// The radius is the weighted RMS of each position's distance to the centroid combined
+/// with its own sigma, scaled to a 68% confidence circle. It grows when the contributing
+/// positions disagree and never claims more precision than the individual positions have.
+fn weighted_position(positions: &[(f64, f64, f64, f64)]) -> Option<(f64, f64, f64)> {
+    let total_weight: f64 = positions.iter().map(|p| p.3).sum();
+    if total_weight <= 0.0 {
+        return None;
+    }
+
+    let lat = positions.iter().map(|p| p.0 * p.3).sum::<f64>() / total_weight;
+    let lng = positions.iter().map(|p| p.1 * p.3).sum::<f64>() / total_weight;
+
+    let variance = positions
+        .iter()
+        .map(|(p_lat, p_lng, sigma, weight)| {
+            let distance = haversine_distance(lat, lng, *p_lat, *p_lng);
+            weight * (distance * distance + sigma * sigma)
+        })
+        .sum::<f64>()
+        / total_weight;
+
+    Some((lat, lng, variance.sqrt() * CONFIDENCE_SCALE))
+}
+
+/// Weighted mean of `(altitude, sigma, weight)` values and its accuracy, computed
+/// like the horizontal radius in `weighted_position`. In one dimension the standard
+/// deviation already covers 68%, so it is not scaled
+fn weighted_altitude(altitudes: &[(f64, f64, f64)]) -> Option<(f64, f64)> {
+    let total_weight: f64 = altitudes.iter().map(|a| a.2).sum();
+    if total_weight <= 0.0 {
+        return None;
+    }
+
+    let altitude = altitudes.iter().map(|a| a.0 * a.2).sum::<f64>() / total_weight;
+    let variance = altitudes
+        .iter()
+        .map(|(a, sigma, weight)| weight * ((a - altitude).powi(2) + sigma * sigma))
+        .sum::<f64>()
+        / total_weight;
+
+    Some((altitude, variance.sqrt()))
+}
+
+/// Groups positions that are within `max_distance` of each other (single linkage)
+/// and returns the indices of the largest group, preferring the heavier one on ties
+fn largest_cluster(positions: &[(f64, f64, f64, f64)], max_distance: f64) -> Vec<usize> {
+    let mut cluster_of: Vec<usize> = (0..positions.len()).collect();
+
+    for i in 0..positions.len() {
+        for j in (i + 1)..positions.len() {
+            let (lat1, lng1, _, _) = positions[i];
+            let (lat2, lng2, _, _) = positions[j];
+            if haversine_distance(lat1, lng1, lat2, lng2) <= max_distance {
+                let (from, to) = (cluster_of[j], cluster_of[i]);
+                if from != to {
+                    cluster_of
+                        .iter_mut()
+                        .filter(|c| **c == from)
+                        .for_each(|c| *c = to);
+                }
+            }
+        }
+    }
+
+    let mut clusters: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (i, cluster) in cluster_of.into_iter().enumerate() {
+        clusters.entry(cluster).or_default().push(i);
+    }
+
+    let total_weight = |c: &Vec<usize>| c.iter().map(|i| positions[*i].3).sum::<f64>();
+    clusters
+        .into_values()
+        .max_by(|a, b| {
+            a.len()
+                .cmp(&b.len())
+                .then(total_weight(a).total_cmp(&total_weight(b)))
+        })
+        .unwrap_or_default()
+}
+
+/// Counts physical APs, treating BSSIDs within `NEAR_IDENTICAL_BSSID_RANGE` of each
+/// other as the same router
+pub fn distinct_aps(bssids: impl IntoIterator<Item = MacAddress>) -> usize {
+    let mut macs: Vec<u64> = bssids.into_iter().map(MacAddress::to_u64).collect();
+    macs.sort_unstable();
+
+    let mut count = 0;
+    let mut router_start: Option<u64> = None;
+    for mac in macs {
+        if router_start.is_none_or(|start| mac - start >= NEAR_IDENTICAL_BSSID_RANGE) {
+            count += 1;
+            router_start = Some(mac);
+        }
+    }
+    count
+}
+
+/// Down-weights stale observations, a scan one minute old counts half
+fn age_weight(age: Option<u32>) -> f64 {
+    1.0 / (1.0 + age.unwrap_or(0) as f64 / 60_000.0)
+}
+
+/// Estimates the distance in meters to an AP with a log-distance path loss model
+fn rssi_to_distance(signal_strength: Option<i32>) -> f64 {
+    // Some clients send 0 when the signal is unknown
+    let rssi = signal_strength
+        .filter(|s| *s < 0)
+        .unwrap_or(WIFI_DEFAULT_SIGNAL) as f64;
+    let exponent = (WIFI_RSSI_AT_ONE_METER - rssi) / (10.0 * WIFI_PATH_LOSS_EXPONENT);
+    10f64.powf(exponent).clamp(1.0, WIFI_MAX_DISTANCE)
+}
+
+pub fn estimate_position_from_aps(
+    response: &AlsLocationResponse,
+    observed: &[WifiAccessPoint],
+    altitude_scale: WifiAltitudeScale,
+) -> Option<MlsResponse> {
+    let observed: HashMap<MacAddress, &WifiAccessPoint> =
+        observed.iter().map(|ap| (ap.mac_address, ap)).collect();
+
+    // Only the APs the client actually saw contribute, Apple's surrounding APs are
+    // context around them and would drag the centroid across the neighborhood
+    let mut positions: Vec<(f64, f64, f64, f64)> = Vec::new();
+    let mut altitudes: Vec<Option<(f64, f64, f64)>> = Vec::new();
+    let mut bssids: Vec<MacAddress> = Vec::new();
+
+    for ap in &response.wireless_aps {
+        // Apple's BSSIDs have no leading zeros in their octets
+        let Some((key, seen)) = ap
+            .mac_id
+            .parse::<MacAddress>()
+            .ok()
+            .and_then(|key| Some((key, observed.get(&key)?)))
+        else {
+            continue;
+        };
+        if let Some(loc) = &ap.location {
+            if let Some((lat, lng, acc)) = loc.to_coordinates() {
+                // The AP position error plus the estimated distance to it
+                let distance = rssi_to_distance(seen.signal_strength);
+                let sigma = (acc as f64).hypot(distance).max(1.0);
+                positions.push((lat, lng, sigma, age_weight(seen.age) / (sigma * sigma)));
+                bssids.push(key);
+                altitudes.push(loc.to_altitude(altitude_scale).map(|(altitude, vertical)| {
+                    let sigma = vertical.max(1.0);
+                    (altitude, sigma, age_weight(seen.age) / (sigma * sigma))
+                }));
+            }
+        }
+    }
+
+    if positions.is_empty() {
+        return None;
+    }
+
+    // A single moved or mislocated AP would pull the average kilometers away,
+    // so only the largest cluster of mutually consistent APs is used
+    let cluster = largest_cluster(&positions, WIFI_CLUSTER_DISTANCE);
+    let rejected = positions.len() - cluster.len();
+
+    // A fix from a single router would reveal that router's location
+    if distinct_aps(cluster.iter().map(|i| bssids[*i])) < 2 {
+        return None;
+    }
+
+    // Weighted average by inverse variance
+    let clustered: Vec<_> = cluster.iter().map(|i| positions[*i]).collect();
+    let (lat, lng, accuracy) = weighted_position(&clustered)?;
+
+    // Floor-level hint from the APs in the cluster that have an altitude
+    let clustered_altitudes: Vec<_> = cluster.iter().filter_map(|i| altitudes[*i]).collect();
+    let altitude = weighted_altitude(&clustered_altitudes);
+
+    Some(MlsResponse {
+        location: Location { lat, lng },
+        accuracy: accuracy.max(WIFI_MIN_ACCURACY),
+        fallback: None,
+        aps_used: Some(cluster.len()),
+        aps_rejected: Some(rejected),
+        altitude: altitude.map(|(altitude, _)| altitude),
+        altitude_accuracy: altitude.map(|(_, accuracy)| accuracy),
+        ..Default::default()
+    })
+}
+
+pub fn estimate_position_from_cells(
+    response: &AlsLocationResponse,
+    observed: &[CellTower],
+) -> Option<MlsResponse> {
+    let observed: HashMap<(i32, i32, i32, i64), &CellTower> = observed
+        .iter()
+        .map(|c| {
+            let key = (
+                c.mobile_country_code,
+                c.mobile_network_code,
+                c.location_area_code,
+                c.cell_id,
+            );
+            (key, c)
+        })
+        .collect();
+
+    // Apple adds up to 25 surrounding cells. Like for APs only the ones the client
+    // reported are used, unless none of them is known
+    let mut positions: Vec<(f64, f64, f64, f64)> = Vec::new();
+    let mut surrounding: Vec<(f64, f64, f64, f64)> = Vec::new();
+    let mut push = |lat: f64, lng: f64, acc: i32, key: (i32, i32, i32, i64)| {
+        let sigma = (acc as f64).max(1.0);
+        match observed.get(&key) {
+            Some(seen) => positions.push((lat, lng, sigma, age_weight(seen.age) / (sigma * sigma))),
+            None => surrounding.push((lat, lng, sigma, 1.0 / (sigma * sigma))),
+        }
+    };
+
+    // Collect from all cell tower types
+    for tower in &response.gsm_cell_towers {
+        if let Some(loc) = &tower.location {
+            if let Some((lat, lng, acc)) = loc.to_coordinates() {
+                let key = (tower.mcc, tower.mnc, tower.lac_id, tower.cell_id as i64);
+                push(lat, lng, acc, key);
+            }
+        }
+    }
+    for tower in &response.lte_cell_towers {
+        if let Some(loc) = &tower.location {
+            if let Some((lat, lng, acc)) = loc.to_coordinates() {
+                let key = (
+                    tower.mcc.unwrap_or_default(),
+                    tower.mnc.unwrap_or_default(),
+                    tower.tac_id.unwrap_or_default(),
+                    tower.cell_id.unwrap_or_default() as i64,
+                );
+                push(lat, lng, acc, key);
+            }
+        }
+    }
+    for tower in &response.scdma_cell_towers {
+        if let Some(loc) = &tower.location {
+            if let Some((lat, lng, acc)) = loc.to_coordinates() {
+                let key = (tower.mcc, tower.mnc, tower.lac_id, tower.cell_id as i64);
+                push(lat, lng, acc, key);
+            }
+        }
+    }
+    for tower in &response.nr5g_cell_towers {
+        if let Some(loc) = &tower.location {
+            if let Some((lat, lng, acc)) = loc.to_coordinates() {
+                let key = (
+                    tower.mcc.unwrap_or_default(),
+                    tower.mnc.unwrap_or_default(),
+                    tower.tac_id.unwrap_or_default(),
+                    tower.cell_id.unwrap_or_default(),
+                );
+                push(lat, lng, acc, key);
+            }
+        }
+    }
+
+    if positions.is_empty() {
+        positions = surrounding;
+    }
+    if positions.is_empty() {
+        return None;
+    }
+
+    // Weighted average by inverse variance
+    let (lat, lng, accuracy) = weighted_position(&positions)?;
+
+    Some(MlsResponse {
+        location: Location { lat, lng },
+        accuracy: accuracy.max(CELL_MIN_ACCURACY),
+        fallback: Some("lacf".to_string()), // Cell tower fallback
+        ..Default::default()
+    })
+}
+
+/// Locates a request from the AP and cell positions in an Apple WPS response
+pub fn estimate_position(
+    response: &AlsLocationResponse,
+    request: &MlsRequest,
+    altitude_scale: WifiAltitudeScale,
+) -> Option<MlsResponse> {
+    let observed_aps = request.wifi_access_points.as_deref().unwrap_or_default();
+    let observed_cells = request.cell_towers.as_deref().unwrap_or_default();
+    let wifi_position = estimate_position_from_aps(response, observed_aps, altitude_scale);
+    let cell_position = estimate_position_from_cells(response, observed_cells);
+
+    fuse_positions(wifi_position, cell_position)
+}
+
+/// A GPS-tagged sighting of one AP or cell tower, as submitted through geosubmit
+#[derive(Clone, Debug, Deserialize)]
+pub struct Sighting {
+    pub lat: f64,
+    pub lng: f64,
+    /// GPS accuracy in meters
+    pub accuracy: Option<f64>,
+    pub signal_strength: Option<i32>,
+    /// Milliseconds between seeing the network and the position fix
+    pub age: Option<u32>,
+    /// Milliseconds since the epoch
+    pub observed_at: u64,
+    /// Milliseconds since the epoch, shared by the sightings of one submission
+    pub submitted_at: u64,
+}
+
+/// Estimates where an AP (`is_wifi`) or cell tower is from sightings of it, with the
+/// same weighting and outlier clustering used for Apple's positions. Returns
+/// `(lat, lng, accuracy)` like `AlsLocation::to_coordinates`
+pub fn estimate_network_position(sightings: &[Sighting], is_wifi: bool) -> Option<(f64, f64, f64)> {
+    let positions: Vec<(f64, f64, f64, f64)> = sightings
+        .iter()
+        .map(|s| {
+            let gps = s.accuracy.unwrap_or(SIGHTING_DEFAULT_ACCURACY);
+            // The AP was somewhere within radio range of the device
+            let sigma = if is_wifi {
+                gps.hypot(rssi_to_distance(s.signal_strength))
+            } else {
+                gps
+            }
+            .max(1.0);
+            (s.lat, s.lng, sigma, age_weight(s.age) / (sigma * sigma))
+        })
+        .collect();
+
+    let (cluster_distance, min_accuracy) = if is_wifi {
+        (WIFI_CLUSTER_DISTANCE, WIFI_MIN_ACCURACY)
+    } else {
+        (CELL_CLUSTER_DISTANCE, CELL_MIN_ACCURACY)
+    };
+    let cluster = largest_cluster(&positions, cluster_distance);
+    let clustered: Vec<_> = cluster.iter().map(|i| positions[*i]).collect();
+    let (lat, lng, accuracy) = weighted_position(&clustered)?;
+
+    Some((lat, lng, accuracy.max(min_accuracy)))
+}
+
+/// Cross-validates a WiFi fix against the cell fix from the same request.
+///
+/// A WiFi fix outside the cells' coverage area comes from APs that move with the
+/// device (travel routers, train WiFi), so the cell result is preferred. When both
+/// agree they are combined by inverse variance.
+pub fn fuse_positions(wifi: Option<MlsResponse>, cell: Option<MlsResponse>) -> Option<MlsResponse> {
+    let (wifi, cell) = match (wifi, cell) {
+        (Some(wifi), Some(cell)) => (wifi, cell),
+        (wifi, cell) => return wifi.or(cell),
+    };
+
+    let distance = haversine_distance(
+        wifi.location.lat,
+        wifi.location.lng,
+        cell.location.lat,
+        cell.location.lng,
+    );
+    if distance > cell.accuracy + wifi.accuracy {
+        return Some(cell);
+    }
+
+    let wifi_weight = 1.0 / wifi.accuracy.max(1.0).powi(2);
+    let cell_weight = 1.0 / cell.accuracy.max(1.0).powi(2);
+    let total_weight = wifi_weight + cell_weight;
+
+    Some(MlsResponse {
+        location: Location {
+            lat: (wifi.location.lat * wifi_weight + cell.location.lat * cell_weight) / total_weight,
+            lng: (wifi.location.lng * wifi_weight + cell.location.lng * cell_weight) / total_weight,
+        },
+        accuracy: total_weight.sqrt().recip(),
+        ..wifi
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apple_wps::{AlsLocation, LteCellTower, WirelessAp};
+
+    /// An AP as seen by the client: offset from the ground truth in meters
+    /// (north, east), Apple's accuracy for it and the client's RSSI
+    struct ApFixture {
+        north: f64,
+        east: f64,
+        accuracy: i32,
+        signal: i32,
+    }
+
+    struct Fixture {
+        name: &'static str,
+        truth: (f64, f64),
+        aps: &'static [ApFixture],
+        max_accuracy: f64,
+        rejected: usize,
+    }
+
+    const fn ap(north: f64, east: f64, accuracy: i32, signal: i32) -> ApFixture {
+        ApFixture {
+            north,
+            east,
+            accuracy,
+            signal,
+        }
+    }
+
+    const FIXTURES: &[Fixture] = &[
+        Fixture {
+            name: "two well-known APs",
+            truth: (45.4642, 9.1900),
+            aps: &[ap(14.0, -6.0, 5, -72), ap(-9.0, 11.0, 8, -70)],
+            max_accuracy: 60.0,
+            rejected: 0,
+        },
+        Fixture {
+            name: "office floor",
+            truth: (52.5200, 13.4050),
+            aps: &[
+                ap(8.0, 3.0, 10, -52),
+                ap(-12.0, 20.0, 15, -68),
+                ap(25.0, -18.0, 20, -74),
+                ap(-30.0, -10.0, 25, -79),
+                ap(5.0, 35.0, 12, -77),
+            ],
+            max_accuracy: 80.0,
+            rejected: 0,
+        },
+        Fixture {
+            name: "street with spread out APs",
+            truth: (48.8566, 2.3522),
+            aps: &[
+                ap(0.0, -160.0, 30, -85),
+                ap(5.0, -90.0, 25, -82),
+                ap(-5.0, 100.0, 25, -83),
+                ap(0.0, 170.0, 30, -86),
+            ],
+            max_accuracy: 300.0,
+            rejected: 0,
+        },
+        Fixture {
+            name: "router that moved house",
+            truth: (41.9028, 12.4964),
+            aps: &[
+                ap(10.0, 10.0, 15, -60),
+                ap(-15.0, 5.0, 20, -70),
+                ap(5.0, -20.0, 15, -66),
+                ap(8_000.0, 3_000.0, 10, -50),
+            ],
+            max_accuracy: 80.0,
+            rejected: 1,
+        },
+    ];
+
+    fn offset(truth: (f64, f64), north: f64, east: f64) -> (f64, f64) {
+        let lat = truth.0 + north / 111_320.0;
+        let lng = truth.1 + east / (111_320.0 * truth.0.to_radians().cos());
+        (lat, lng)
+    }
+
+    fn als_location(lat: f64, lng: f64, accuracy: i32) -> AlsLocation {
+        AlsLocation {
+            latitude: (lat * 1e8).round() as i64,
+            longitude: (lng * 1e8).round() as i64,
+            accuracy,
+            ..Default::default()
+        }
+    }
+
+    fn build(fixture: &Fixture) -> (AlsLocationResponse, Vec<WifiAccessPoint>) {
+        let mut response = AlsLocationResponse::default();
+        let mut observed = Vec::new();
+
+        for (i, ap) in fixture.aps.iter().enumerate() {
+            let (lat, lng) = offset(fixture.truth, ap.north, ap.east);
+            // Apple strips leading zeros from each octet
+            response.wireless_aps.push(WirelessAp {
+                mac_id: format!("0:11:22:{:x}:44:55", i),
+                location: Some(als_location(lat, lng, ap.accuracy)),
+                channel: None,
+            });
+            observed.push(WifiAccessPoint {
+                mac_address: format!("00:11:22:{:02x}:44:55", i).parse().unwrap(),
+                signal_strength: Some(ap.signal),
+                age: None,
+                channel: None,
+                signal_to_noise_ratio: None,
+                ssid: None,
+            });
+        }
+
+        (response, observed)
+    }
+
+    #[test]
+    fn wifi_accuracy_contains_ground_truth() {
+        for fixture in FIXTURES {
+            let (response, observed) = build(fixture);
+            let fix =
+                estimate_position_from_aps(&response, &observed, WifiAltitudeScale::TenToThe2)
+                    .unwrap();
+            let error = haversine_distance(
+                fixture.truth.0,
+                fixture.truth.1,
+                fix.location.lat,
+                fix.location.lng,
+            );
+
+            assert!(
+                error <= fix.accuracy,
+                "{}: error {:.1} m outside accuracy {:.1} m",
+                fixture.name,
+                error,
+                fix.accuracy
+            );
+            assert!(
+                fix.accuracy <= fixture.max_accuracy,
+                "{}: accuracy {:.1} m above {:.1} m",
+                fixture.name,
+                fix.accuracy,
+                fixture.max_accuracy
+            );
+            assert_eq!(fix.aps_rejected, Some(fixture.rejected), "{}", fixture.name);
+        }
+    }
+
+    #[test]
+    fn surrounding_aps_are_ignored() {
+        let fixture = &FIXTURES[1];
+        let (mut response, observed) = build(fixture);
+        let (lat, lng) = offset(fixture.truth, 300.0, 300.0);
+        response.wireless_aps.push(WirelessAp {
+            mac_id: "aa:bb:cc:dd:ee:ff".to_string(),
+            location: Some(als_location(lat, lng, 10)),
+            channel: None,
+        });
+
+        let fix =
+            estimate_position_from_aps(&response, &observed, WifiAltitudeScale::TenToThe2).unwrap();
+        assert_eq!(fix.aps_used, Some(fixture.aps.len()));
+    }
+
+    #[test]
+    fn single_router_is_not_located() {
+        let truth = (45.4642, 9.1900);
+        let (lat, lng) = offset(truth, 5.0, 5.0);
+        let mut response = AlsLocationResponse::default();
+        let mut observed = Vec::new();
+        // The 2.4 and 5 GHz radios of one router
+        for mac in ["00:11:22:33:44:50", "00:11:22:33:44:51"] {
+            response.wireless_aps.push(WirelessAp {
+                mac_id: mac.to_string(),
+                location: Some(als_location(lat, lng, 5)),
+                channel: None,
+            });
+            observed.push(WifiAccessPoint {
+                mac_address: mac.parse().unwrap(),
+                signal_strength: Some(-60),
+                age: None,
+                channel: None,
+                signal_to_noise_ratio: None,
+                ssid: None,
+            });
+        }
+
+        let fix = estimate_position_from_aps(&response, &observed, WifiAltitudeScale::TenToThe2);
+        assert!(fix.is_none());
+
+        let single = &observed[..1];
+        let fix = estimate_position_from_aps(&response, single, WifiAltitudeScale::TenToThe2);
+        assert!(fix.is_none());
+    }
+
+    /// Locates the "two well-known APs" fixture with the given per-AP altitude and
+    /// vertical accuracy, in Apple's units for `scale`
+    fn altitude_fix(
+        altitudes: [(Option<i32>, Option<i32>); 2],
+        scale: WifiAltitudeScale,
+    ) -> MlsResponse {
+        let (mut response, observed) = build(&FIXTURES[0]);
+        for (ap, (altitude, vertical_accuracy)) in response.wireless_aps.iter_mut().zip(altitudes) {
+            let location = ap.location.as_mut().unwrap();
+            location.altitude = altitude;
+            location.vertical_accuracy = vertical_accuracy;
+        }
+        estimate_position_from_aps(&response, &observed, scale).unwrap()
+    }
+
+    #[test]
+    fn altitudes_are_scaled_and_combined() {
+        // Centimeters: 41.5 m and 44.5 m, both within 3 m
+        let fix = altitude_fix(
+            [(Some(4150), Some(300)), (Some(4450), Some(300))],
+            WifiAltitudeScale::TenToThe2,
+        );
+        assert!((fix.altitude.unwrap() - 43.0).abs() < 1e-9);
+        // The spread of 1.5 m combined with each AP's 3 m
+        let expected = (1.5f64.powi(2) + 3.0f64.powi(2)).sqrt();
+        assert!((fix.altitude_accuracy.unwrap() - expected).abs() < 1e-9);
+
+        // Meters
+        let fix = altitude_fix(
+            [(Some(41), Some(3)), (Some(45), Some(3))],
+            WifiAltitudeScale::None,
+        );
+        assert!((fix.altitude.unwrap() - 43.0).abs() < 1e-9);
+        let expected = (2.0f64.powi(2) + 3.0f64.powi(2)).sqrt();
+        assert!((fix.altitude_accuracy.unwrap() - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn altitudes_without_accuracy_are_ignored() {
+        // The first AP has no vertical accuracy, the second one is taken as is
+        let fix = altitude_fix(
+            [(Some(9000), None), (Some(4450), Some(250))],
+            WifiAltitudeScale::TenToThe2,
+        );
+        assert!((fix.altitude.unwrap() - 44.5).abs() < 1e-9);
+        assert!((fix.altitude_accuracy.unwrap() - 2.5).abs() < 1e-9);
+
+        let fix = altitude_fix([(None, None), (None, None)], WifiAltitudeScale::TenToThe2);
+        assert_eq!((fix.altitude, fix.altitude_accuracy), (None, None));
+    }
+
+    #[test]
+    fn cell_accuracy_contains_ground_truth() {
+        let truth = (40.4168, -3.7038);
+        let mut response = AlsLocationResponse::default();
+        for (north, east) in [(800.0, 0.0), (-600.0, 500.0), (-200.0, -900.0)] {
+            let (lat, lng) = offset(truth, north, east);
+            response.lte_cell_towers.push(LteCellTower {
+                location: Some(als_location(lat, lng, 1500)),
+                ..Default::default()
+            });
+        }
+
+        let fix = estimate_position_from_cells(&response, &[]).unwrap();
+        let error = haversine_distance(truth.0, truth.1, fix.location.lat, fix.location.lng);
+        assert!(error <= fix.accuracy);
+        assert!(fix.accuracy <= 3000.0);
+    }
+
+    /// Deterministic standard normal samples (xorshift and Box-Muller)
+    fn normal_samples(count: usize) -> Vec<(f64, f64)> {
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut uniform = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 11) as f64 / (1u64 << 53) as f64
+        };
+        (0..count)
+            .map(|_| {
+                let radius = (-2.0 * (1.0 - uniform()).ln()).sqrt();
+                let angle = std::f64::consts::TAU * uniform();
+                (radius * angle.cos(), radius * angle.sin())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn accuracy_radius_covers_68_percent() {
+        let truth = (52.5200, 13.4050);
+        let sigma = 40.0;
+        let samples: Vec<(f64, f64)> = normal_samples(4000)
+            .into_iter()
+            .map(|(north, east)| offset(truth, north * sigma, east * sigma))
+            .collect();
+        let positions: Vec<_> = samples
+            .iter()
+            .map(|(lat, lng)| (*lat, *lng, 0.0, 1.0))
+            .collect();
+
+        let (_, _, radius) = weighted_position(&positions).unwrap();
+        let covered = samples
+            .iter()
+            .filter(|(lat, lng)| haversine_distance(truth.0, truth.1, *lat, *lng) <= radius)
+            .count() as f64
+            / samples.len() as f64;
+
+        // An inflated radius would hold far more than 68% of the sampled fixes
+        assert!(
+            (0.64..=0.72).contains(&covered),
+            "radius {:.1} m covers {:.1}% of fixes",
+            radius,
+            covered * 100.0
+        );
+    }
+
+    #[test]
+    fn surrounding_cells_are_ignored() {
+        let serving = (40.4168, -3.7038);
+        let mut response = AlsLocationResponse::default();
+        for (cell_id, north, east) in [(1, 0.0, 0.0), (2, 4_000.0, 3_000.0), (3, 5_000.0, -2_000.0)]
+        {
+            let (lat, lng) = offset(serving, north, east);
+            response.lte_cell_towers.push(LteCellTower {
+                mcc: Some(214),
+                mnc: Some(1),
+                tac_id: Some(1234),
+                cell_id: Some(cell_id),
+                location: Some(als_location(lat, lng, 800)),
+            });
+        }
+        let observed = [CellTower {
+            radio_type: Some("lte".to_string()),
+            mobile_country_code: 214,
+            mobile_network_code: 1,
+            location_area_code: 1234,
+            cell_id: 1,
+            age: None,
+            signal_strength: Some(-90),
+            timing_advance: None,
+        }];
+
+        let fix = estimate_position_from_cells(&response, &observed).unwrap();
+        let error = haversine_distance(serving.0, serving.1, fix.location.lat, fix.location.lng);
+        assert!(error < 1.0, "moved {:.1} m towards the neighbours", error);
+        assert!(fix.accuracy <= 1000.0, "accuracy {:.1} m", fix.accuracy);
+
+        // Without a reported cell Apple knows, the surrounding ones are all there is
+        let fix = estimate_position_from_cells(&response, &[]).unwrap();
+        let error = haversine_distance(serving.0, serving.1, fix.location.lat, fix.location.lng);
+        assert!(error > 1_000.0);
+    }
+
+    fn fix(position: (f64, f64), accuracy: f64, fallback: Option<&str>) -> MlsResponse {
+        MlsResponse {
+            location: Location {
+                lat: position.0,
+                lng: position.1,
+            },
+            accuracy,
+            fallback: fallback.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn travel_router_falls_back_to_cells() {
+        let cell_position = (50.1109, 8.6821);
+        // The router on a train still reports where it was surveyed
+        let wifi = fix(offset(cell_position, 20_000.0, 5_000.0), 25.0, None);
+        let cell = fix(cell_position, 1500.0, Some("lacf"));
+
+        let fused = fuse_positions(Some(wifi), Some(cell)).unwrap();
+        assert_eq!(fused.fallback.as_deref(), Some("lacf"));
+        assert_eq!(fused.accuracy, 1500.0);
+        assert_eq!(
+            (fused.location.lat, fused.location.lng),
+            (cell_position.0, cell_position.1)
+        );
+    }
+
+    #[test]
+    fn agreeing_wifi_and_cells_are_combined() {
+        let cell_position = (50.1109, 8.6821);
+        let wifi_position = offset(cell_position, 400.0, -300.0);
+        let wifi = MlsResponse {
+            aps_used: Some(4),
+            ..fix(wifi_position, 25.0, None)
+        };
+        let cell = fix(cell_position, 1500.0, Some("lacf"));
+
+        let fused = fuse_positions(Some(wifi), Some(cell)).unwrap();
+        assert_eq!(fused.fallback, None);
+        assert_eq!(fused.aps_used, Some(4));
+        assert!(fused.accuracy <= 25.0, "accuracy {:.1} m", fused.accuracy);
+        // The far more precise WiFi fix dominates the combination
+        let shift = haversine_distance(
+            wifi_position.0,
+            wifi_position.1,
+            fused.location.lat,
+            fused.location.lng,
+        );
+        assert!(shift < 1.0, "moved {:.1} m from the WiFi fix", shift);
+    }
+}
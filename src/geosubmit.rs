@@ -0,0 +1,389 @@
+// Ichnaea-compatible `/v2/geosubmit`, storing crowdsourced observations in D1
+use serde::Deserialize;
+use worker::wasm_bindgen::JsValue;
+use worker::{D1Database, Date, Env};
+
+use crate::position_cache::{cell_key, wifi_key};
+use crate::{parse_json, CellTower, WifiAccessPoint};
+
+const D1_BINDING: &str = "OBSERVATIONS";
+// Submissions are written in one D1 batch, so they are stored entirely or not at all.
+// Larger uploads have to be split by the client
+const MAX_ITEMS: usize = 100;
+const MAX_NETWORKS: usize = 500;
+// Items observed longer ago than this are ignored, later timestamps are clamped to
+// the time of submission
+const MAX_OBSERVATION_AGE_MS: u64 = 30 * 24 * 60 * 60 * 1000;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeosubmitRequest {
+    items: Vec<GeosubmitItem>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GeosubmitItem {
+    /// Milliseconds since the epoch
+    #[serde(default)]
+    timestamp: Option<u64>,
+    #[serde(default)]
+    position: Option<Position>,
+    #[serde(default)]
+    cell_towers: Vec<GeosubmitCell>,
+    #[serde(default)]
+    wifi_access_points: Vec<WifiAccessPoint>,
+}
+
+/// A cell as geosubmit reports it. Unlike in geolocate requests the cell identity
+/// may be missing, for neighbours only known by their PSC or PCI
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GeosubmitCell {
+    #[serde(default)]
+    radio_type: Option<String>,
+    mobile_country_code: i32,
+    mobile_network_code: i32,
+    #[serde(default)]
+    location_area_code: Option<i32>,
+    #[serde(default)]
+    cell_id: Option<i64>,
+    #[serde(default)]
+    age: Option<u32>,
+    #[serde(default)]
+    signal_strength: Option<i32>,
+}
+
+impl GeosubmitCell {
+    /// `None` for cells without a full identity, those cannot be located
+    fn to_cell_tower(&self) -> Option<CellTower> {
+        Some(CellTower {
+            radio_type: self.radio_type.clone(),
+            mobile_country_code: self.mobile_country_code,
+            mobile_network_code: self.mobile_network_code,
+            location_area_code: self.location_area_code?,
+            cell_id: self.cell_id?,
+            age: self.age,
+            signal_strength: self.signal_strength,
+            timing_advance: None,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Position {
+    latitude: f64,
+    longitude: f64,
+    #[serde(default)]
+    accuracy: Option<f64>,
+    #[serde(default)]
+    altitude: Option<f64>,
+    #[serde(default)]
+    altitude_accuracy: Option<f64>,
+}
+
+impl Position {
+    fn validate(&self) -> Result<(), String> {
+        if !(-90.0..=90.0).contains(&self.latitude) {
+            return Err(format!("latitude: {} is out of range", self.latitude));
+        }
+        if !(-180.0..=180.0).contains(&self.longitude) {
+            return Err(format!("longitude: {} is out of range", self.longitude));
+        }
+        if let Some(accuracy) = self.accuracy.filter(|a| a.is_nan() || *a < 0.0) {
+            return Err(format!("accuracy: {} is out of range", accuracy));
+        }
+        Ok(())
+    }
+}
+
+/// One network seen at a position, a row of the `observations` table
+struct Observation {
+    network_key: String,
+    lat: f64,
+    lng: f64,
+    accuracy: Option<f64>,
+    altitude: Option<f64>,
+    altitude_accuracy: Option<f64>,
+    signal_strength: Option<i32>,
+    age: Option<u32>,
+    observed_at: u64,
+}
+
+impl GeosubmitRequest {
+    /// Parses and validates a submission with the same rules as geolocate requests,
+    /// rejecting those with more than `MAX_ITEMS` items or `MAX_NETWORKS` networks
+    pub fn parse(body: &str) -> Result<Self, String> {
+        let request: GeosubmitRequest = parse_json(body)?;
+
+        if request.items.len() > MAX_ITEMS {
+            return Err(format!(
+                "items: {} items exceed the limit of {}",
+                request.items.len(),
+                MAX_ITEMS
+            ));
+        }
+        let networks: usize = request
+            .items
+            .iter()
+            .map(|item| item.wifi_access_points.len() + item.cell_towers.len())
+            .sum();
+        if networks > MAX_NETWORKS {
+            return Err(format!(
+                "items: {} networks exceed the limit of {}",
+                networks, MAX_NETWORKS
+            ));
+        }
+
+        for (i, item) in request.items.iter().enumerate() {
+            if let Some(position) = &item.position {
+                position
+                    .validate()
+                    .map_err(|e| format!("items[{}].position.{}", i, e))?;
+            }
+            for (j, cell) in item.cell_towers.iter().enumerate() {
+                if let Some(cell) = cell.to_cell_tower() {
+                    cell.validate(None)
+                        .map_err(|e| format!("items[{}].cellTowers[{}].{}", i, j, e))?;
+                }
+            }
+        }
+
+        Ok(request)
+    }
+
+    /// Flattens the items into one observation per network. Items without a
+    /// position, opted-out and randomized APs are ignored, as Ichnaea does, and so
+    /// are cells without an identity and items older than `MAX_OBSERVATION_AGE_MS`
+    fn observations(&self, now: u64) -> Vec<Observation> {
+        let oldest = now.saturating_sub(MAX_OBSERVATION_AGE_MS);
+        let mut observations = Vec::new();
+
+        for item in &self.items {
+            let Some(position) = &item.position else {
+                continue;
+            };
+            // Device clocks running ahead must not date observations into the future
+            let observed_at = match item.timestamp {
+                Some(timestamp) if timestamp < oldest => continue,
+                Some(timestamp) => timestamp.min(now),
+                None => now,
+            };
+            let observation = |network_key: String, signal_strength, age| Observation {
+                network_key,
+                lat: position.latitude,
+                lng: position.longitude,
+                accuracy: position.accuracy,
+                altitude: position.altitude,
+                altitude_accuracy: position.altitude_accuracy,
+                signal_strength,
+                age,
+                observed_at,
+            };
+
+            for ap in item
+                .wifi_access_points
+                .iter()
+                .filter(|ap| ap.is_locatable())
+            {
+                observations.push(observation(
+                    wifi_key(ap.mac_address),
+                    ap.signal_strength,
+                    ap.age,
+                ));
+            }
+            for cell in item
+                .cell_towers
+                .iter()
+                .filter_map(GeosubmitCell::to_cell_tower)
+            {
+                let cell_request = cell.to_request(None);
+                let key = cell_key(
+                    &cell_request.radio_type,
+                    cell_request.mcc,
+                    cell_request.mnc,
+                    cell_request.lac,
+                    cell_request.cell_id,
+                );
+                observations.push(observation(key, cell.signal_strength, cell.age));
+            }
+        }
+
+        observations
+    }
+}
+
+fn nullable<T: Into<JsValue>>(value: Option<T>) -> JsValue {
+    value.map(Into::into).unwrap_or(JsValue::NULL)
+}
+
+/// The `OBSERVATIONS` D1 database, if bound
+pub fn database(env: &Env) -> Option<D1Database> {
+    env.d1(D1_BINDING).ok()
+}
+
+/// Stores every observation in the submission, returning how many were stored
+pub async fn store(db: &D1Database, request: &GeosubmitRequest) -> worker::Result<usize> {
+    let submitted_at = Date::now().as_millis();
+    let observations = request.observations(submitted_at);
+    let insert = db.prepare(
+        "INSERT INTO observations (network_key, lat, lng, accuracy, altitude, \
+         altitude_accuracy, signal_strength, age, observed_at, submitted_at) \
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+    );
+
+    if observations.is_empty() {
+        return Ok(0);
+    }
+
+    let statements = observations
+        .iter()
+        .map(|o| {
+            insert.clone().bind(&[
+                o.network_key.as_str().into(),
+                o.lat.into(),
+                o.lng.into(),
+                nullable(o.accuracy),
+                nullable(o.altitude),
+                nullable(o.altitude_accuracy),
+                nullable(o.signal_strength),
+                nullable(o.age),
+                (o.observed_at as f64).into(),
+                (submitted_at as f64).into(),
+            ])
+        })
+        .collect::<worker::Result<Vec<_>>>()?;
+    // A batch runs as one transaction, a failed submission can be retried as a whole
+    db.batch(statements).await?;
+
+    Ok(observations.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000_000;
+    const POSITION: &str = r#""position": {"latitude": 52.5163, "longitude": 13.3777}"#;
+
+    fn submission(items: &[String]) -> String {
+        format!(r#"{{"items": [{}]}}"#, items.join(","))
+    }
+
+    fn item_with_wifis(count: usize) -> String {
+        let aps: Vec<String> = (0..count)
+            .map(|i| {
+                format!(
+                    r#"{{"macAddress": "00:11:22:33:{:02x}:{:02x}"}}"#,
+                    i / 256,
+                    i % 256
+                )
+            })
+            .collect();
+        format!(
+            r#"{{{}, "wifiAccessPoints": [{}]}}"#,
+            POSITION,
+            aps.join(",")
+        )
+    }
+
+    fn item_at(timestamp: u64) -> String {
+        format!(
+            r#"{{"timestamp": {}, {}, "wifiAccessPoints": [{{"macAddress": "00:11:22:33:44:55"}}]}}"#,
+            timestamp, POSITION
+        )
+    }
+
+    #[test]
+    fn submission_size_is_capped() {
+        let items = vec![item_with_wifis(1); MAX_ITEMS];
+        assert!(GeosubmitRequest::parse(&submission(&items)).is_ok());
+
+        let items = vec![item_with_wifis(1); MAX_ITEMS + 1];
+        let error = GeosubmitRequest::parse(&submission(&items)).unwrap_err();
+        assert!(error.starts_with("items: 101 items"), "{}", error);
+
+        let items = [item_with_wifis(MAX_NETWORKS)];
+        assert!(GeosubmitRequest::parse(&submission(&items)).is_ok());
+
+        let items = [item_with_wifis(MAX_NETWORKS), item_with_wifis(1)];
+        let error = GeosubmitRequest::parse(&submission(&items)).unwrap_err();
+        assert!(error.starts_with("items: 501 networks"), "{}", error);
+    }
+
+    #[test]
+    fn positions_are_validated() {
+        for (position, field) in [
+            (r#"{"latitude": 90.5, "longitude": 0}"#, "latitude"),
+            (r#"{"latitude": 0, "longitude": -180.5}"#, "longitude"),
+            (
+                r#"{"latitude": 0, "longitude": 0, "accuracy": -1}"#,
+                "accuracy",
+            ),
+        ] {
+            let body = submission(&[
+                item_with_wifis(1),
+                format!(r#"{{"position": {}}}"#, position),
+            ]);
+            let error = GeosubmitRequest::parse(&body).unwrap_err();
+            let prefix = format!("items[1].position.{}: ", field);
+            assert!(error.starts_with(&prefix), "{}", error);
+        }
+    }
+
+    #[test]
+    fn timestamps_are_bounded() {
+        let body = submission(&[
+            item_at(NOW - 1000),
+            // Beyond the oldest accepted age
+            item_at(NOW - MAX_OBSERVATION_AGE_MS - 1),
+            // A device clock running an hour ahead
+            item_at(NOW + 3_600_000),
+            format!(
+                r#"{{{}, "wifiAccessPoints": [{{"macAddress": "00:11:22:33:44:55"}}]}}"#,
+                POSITION
+            ),
+        ]);
+        let request = GeosubmitRequest::parse(&body).unwrap();
+        let observed_at: Vec<u64> = request
+            .observations(NOW)
+            .iter()
+            .map(|o| o.observed_at)
+            .collect();
+
+        assert_eq!(observed_at, [NOW - 1000, NOW, NOW]);
+    }
+
+    #[test]
+    fn cells_without_identity_are_skipped() {
+        let body = format!(
+            r#"{{"items": [{{{}, "cellTowers": [
+                {{"radioType": "wcdma", "mobileCountryCode": 262, "mobileNetworkCode": 1,
+                  "primaryScramblingCode": 312}},
+                {{"radioType": "lte", "mobileCountryCode": 262, "mobileNetworkCode": 1,
+                  "locationAreaCode": 1234, "cellId": 567890}}]}}]}}"#,
+            POSITION
+        );
+        let request = GeosubmitRequest::parse(&body).unwrap();
+        let keys: Vec<String> = request
+            .observations(NOW)
+            .into_iter()
+            .map(|o| o.network_key)
+            .collect();
+        assert_eq!(keys, ["cell:lte:262:1:1234:567890"]);
+
+        // Cells with an identity are still validated
+        let body = format!(
+            r#"{{"items": [{{{}, "cellTowers": [{{"mobileCountryCode": 262,
+                "mobileNetworkCode": 1, "locationAreaCode": 1, "cellId": -1}}]}}]}}"#,
+            POSITION
+        );
+        let error = GeosubmitRequest::parse(&body).unwrap_err();
+        assert!(
+            error.starts_with("items[0].cellTowers[0].cellId: "),
+            "{}",
+            error
+        );
+    }
+}
@@ -1,61 +1,127 @@
+mod aggregate;
 mod apple_wps;
-
-use apple_wps::{AlsLocationRequest, AlsLocationResponse, CellRequest};
-use bytes::{BufMut, BytesMut};
-use prost::Message;
+mod backend;
+mod breaker;
+mod config;
+mod countries;
+mod estimate;
+mod geosubmit;
+mod local_dataset;
+mod mac;
+mod position_cache;
+mod response_cache;
+
+use apple_wps::{CellRequest, WifiRequest};
+use mac::MacAddress;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use worker::*;
 
-const GRAPHENEOS_PROXY_URL: &str = "https://gs-loc.apple.grapheneos.org/clls/wloc";
+// GSM cell identities are 16 bits, UMTS and LTE ones 28 bits and 5G NR
+// cell identities (NCI) 36 bits wide
+const GSM_MAX_CELL_ID: i64 = (1 << 16) - 1;
+const MAX_CELL_ID: i64 = (1 << 28) - 1;
+const NR_MAX_CELL_ID: i64 = (1 << 36) - 1;
 
 // MLS Request types
 #[derive(Debug, Deserialize, Serialize, Default)]
 #[serde(rename_all = "camelCase")]
 struct MlsRequest {
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     consider_ip: Option<bool>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     radio_type: Option<String>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     cell_towers: Option<Vec<CellTower>>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     wifi_access_points: Option<Vec<WifiAccessPoint>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    home_mobile_country_code: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    home_mobile_network_code: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    carrier: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct CellTower {
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     radio_type: Option<String>,
     mobile_country_code: i32,
     mobile_network_code: i32,
     location_area_code: i32,
-    cell_id: i32,
+    /// 16 bits for `gsm`, 28 bits for `wcdma` and `lte`, the 36-bit NCI for `nr`
+    cell_id: i64,
+    /// Milliseconds since the cell was last seen
+    #[serde(skip_serializing_if = "Option::is_none")]
+    age: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signal_strength: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timing_advance: Option<i32>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct WifiAccessPoint {
-    mac_address: String,
+    mac_address: MacAddress,
     #[serde(skip_serializing_if = "Option::is_none")]
     signal_strength: Option<i32>,
+    /// Milliseconds since the access point was last seen
+    #[serde(skip_serializing_if = "Option::is_none")]
+    age: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    channel: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signal_to_noise_ratio: Option<i32>,
+    /// Networks whose SSID ends in `_nomap` have opted out of location services
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ssid: Option<String>,
 }
 
 // MLS Response types
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Default)]
+#[serde(rename_all = "camelCase")]
 struct MlsResponse {
     location: Location,
     accuracy: f64,
     #[serde(skip_serializing_if = "Option::is_none")]
     fallback: Option<String>,
+    /// Client-reported APs that contributed to a WiFi fix
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aps_used: Option<usize>,
+    /// Client-reported APs discarded as outliers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aps_rejected: Option<usize>,
+    /// Meters above sea level, combined from Apple's per-AP altitudes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    altitude: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    altitude_accuracy: Option<f64>,
+    /// Reported APs left out because they opted out or are not fixed access points
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aps_filtered: Option<usize>,
+    /// The backend that produced the fix, `cache` for the edge cache, `response-cache`
+    /// for a cached response, absent for IP fallback
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backend: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Default)]
 struct Location {
     lat: f64,
     lng: f64,
 }
 
+#[derive(Debug, Serialize)]
+struct MlsRegionResponse {
+    country_code: String,
+    country_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fallback: Option<String>,
+}
+
 #[derive(Debug, Serialize)]
 struct MlsError {
     error: MlsErrorDetail,
@@ -94,10 +160,17 @@ impl MlsRequest {
         self.has_wifi_data() || self.has_cell_data()
     }
 
-    fn get_bssids(&self) -> Vec<String> {
+    fn get_wifis(&self) -> Vec<WifiRequest> {
         self.wifi_access_points
             .as_ref()
-            .map(|aps| aps.iter().map(|ap| normalize_bssid(&ap.mac_address)).collect())
+            .map(|aps| {
+                aps.iter()
+                    .map(|ap| WifiRequest {
+                        bssid: ap.mac_address.to_string(),
+                        channel: ap.channel,
+                    })
+                    .collect()
+            })
             .unwrap_or_default()
     }
 
@@ -107,47 +180,176 @@ impl MlsRequest {
             .map(|cells| {
                 cells
                     .iter()
-                    .map(|c| {
-                        let radio = c
-                            .radio_type
-                            .as_ref()
-                            .or(global_radio_type.as_ref())
-                            .cloned()
-                            .unwrap_or_else(|| "lte".to_string());
-                        CellRequest {
-                            radio_type: radio,
-                            mcc: c.mobile_country_code,
-                            mnc: c.mobile_network_code,
-                            lac: c.location_area_code,
-                            cell_id: c.cell_id,
-                        }
-                    })
+                    .map(|c| c.to_request(global_radio_type.as_deref()))
                     .collect()
             })
             .unwrap_or_default()
     }
+
+    /// Parses a request body, rejecting malformed JSON and wrong field types
+    fn parse(body: &str) -> std::result::Result<Self, String> {
+        // An empty body is an IP-only lookup, same as `{}`
+        if body.trim().is_empty() {
+            return Ok(MlsRequest::default());
+        }
+
+        let request: MlsRequest = parse_json(body)?;
+        request.validate()?;
+        Ok(request)
+    }
+
+    /// Removes the APs that must not be used for positioning, returning how many
+    fn drop_unlocatable_wifis(&mut self) -> usize {
+        let Some(aps) = &mut self.wifi_access_points else {
+            return 0;
+        };
+        let before = aps.len();
+        aps.retain(WifiAccessPoint::is_locatable);
+        before - aps.len()
+    }
+
+    /// Ignores the WiFi data unless it contains `min_distinct` physical APs, so
+    /// requests cannot resolve a single router. Returns how many APs were ignored
+    fn enforce_min_distinct_aps(&mut self, min_distinct: usize) -> usize {
+        let aps = self.wifi_access_points.as_deref().unwrap_or_default();
+        if estimate::distinct_aps(aps.iter().map(|ap| ap.mac_address)) >= min_distinct {
+            return 0;
+        }
+        self.wifi_access_points.take().map_or(0, |aps| aps.len())
+    }
+
+    fn validate(&self) -> std::result::Result<(), String> {
+        if let Some(mcc) = self.home_mobile_country_code {
+            if !(1..=999).contains(&mcc) {
+                return Err(format!("homeMobileCountryCode: {} is out of range", mcc));
+            }
+        }
+        if let Some(mnc) = self.home_mobile_network_code {
+            if !(0..=999).contains(&mnc) {
+                return Err(format!("homeMobileNetworkCode: {} is out of range", mnc));
+            }
+        }
+
+        for (i, cell) in self.cell_towers.iter().flatten().enumerate() {
+            cell.validate(self.radio_type.as_deref())
+                .map_err(|e| format!("cellTowers[{}].{}", i, e))?;
+        }
+
+        Ok(())
+    }
 }
 
-fn normalize_bssid(mac: &str) -> String {
-    // Apple accepts both formats, but normalize to lowercase with colons
-    mac.to_lowercase()
-        .replace('-', ":")
+impl CellTower {
+    /// The cell's radio type, falling back to the request's and then to "lte"
+    fn radio<'a>(&'a self, default_radio: Option<&'a str>) -> &'a str {
+        self.radio_type
+            .as_deref()
+            .or(default_radio)
+            .unwrap_or("lte")
+    }
+
+    fn to_request(&self, default_radio: Option<&str>) -> CellRequest {
+        CellRequest {
+            radio_type: self.radio(default_radio).to_string(),
+            mcc: self.mobile_country_code,
+            mnc: self.mobile_network_code,
+            lac: self.location_area_code,
+            cell_id: self.cell_id,
+        }
+    }
+
+    /// Checks MCC, MNC and the cell ID range of the radio type. Errors name the field
+    fn validate(&self, default_radio: Option<&str>) -> std::result::Result<(), String> {
+        if !(1..=999).contains(&self.mobile_country_code) {
+            return Err(format!(
+                "mobileCountryCode: {} is out of range",
+                self.mobile_country_code
+            ));
+        }
+        if !(0..=999).contains(&self.mobile_network_code) {
+            return Err(format!(
+                "mobileNetworkCode: {} is out of range",
+                self.mobile_network_code
+            ));
+        }
+        let radio = self.radio(default_radio);
+        let max_cell_id = match radio {
+            "gsm" => GSM_MAX_CELL_ID,
+            "nr" => NR_MAX_CELL_ID,
+            _ => MAX_CELL_ID,
+        };
+        if !(0..=max_cell_id).contains(&self.cell_id) {
+            return Err(format!(
+                "cellId: {} is out of range for radioType {:?}",
+                self.cell_id, radio
+            ));
+        }
+
+        Ok(())
+    }
 }
 
-fn build_error_response() -> MlsError {
+impl WifiAccessPoint {
+    /// Whether the AP may be used for positioning: not opted out with a `_nomap`
+    /// SSID and a globally unique unicast BSSID. Locally administered addresses are
+    /// randomized or belong to hotspots that move with their owner
+    fn is_locatable(&self) -> bool {
+        if self
+            .ssid
+            .as_deref()
+            .is_some_and(|ssid| ssid.ends_with("_nomap"))
+        {
+            return false;
+        }
+        let mac = self.mac_address;
+        !mac.is_multicast() && !mac.is_locally_administered() && !mac.is_zero()
+    }
+}
+
+/// Deserializes a JSON body, naming the offending field in type errors
+fn parse_json<T: DeserializeOwned>(body: &str) -> std::result::Result<T, String> {
+    let deserializer = &mut serde_json::Deserializer::from_str(body);
+    serde_path_to_error::deserialize(deserializer).map_err(|e| {
+        // Only type errors can be attributed to a field
+        if e.inner().is_data() {
+            format!("{}: {}", e.path(), e.inner())
+        } else {
+            e.inner().to_string()
+        }
+    })
+}
+
+fn build_error(code: u16, domain: &str, reason: &str, message: &str) -> MlsError {
     MlsError {
         error: MlsErrorDetail {
             errors: vec![MlsErrorItem {
-                domain: "geolocation".to_string(),
-                reason: "notFound".to_string(),
-                message: "Not found".to_string(),
+                domain: domain.to_string(),
+                reason: reason.to_string(),
+                message: message.to_string(),
             }],
-            code: 404,
-            message: "Not found".to_string(),
+            code,
+            message: message.to_string(),
         },
     }
 }
 
+fn build_error_response() -> MlsError {
+    build_error(404, "geolocation", "notFound", "Not found")
+}
+
+fn build_parse_error_response(reason: &str) -> MlsError {
+    build_error(
+        400,
+        "global",
+        "parseError",
+        &format!("Parse Error: {}", reason),
+    )
+}
+
+fn build_service_unavailable_response() -> MlsError {
+    build_error(503, "global", "serviceUnavailable", "Service unavailable")
+}
+
 fn build_cloudflare_response(cf: &Cf) -> Option<MlsResponse> {
     let (lat, lng) = cf.coordinates()?;
 
@@ -168,247 +370,263 @@ fn build_cloudflare_response(cf: &Cf) -> Option<MlsResponse> {
         },
         accuracy,
         fallback: Some("ipf".to_string()),
+        ..Default::default()
     })
 }
 
-fn build_apple_request_body(request: &AlsLocationRequest) -> Vec<u8> {
-    let locale = b"en_US";
-    let identifier = b"com.apple.locationd";
-    let version = b"15.4.24E248";
-    let request_code: i32 = 1;
-
-    let proto_bytes = request.encode_to_vec();
-
-    let mut buf = BytesMut::with_capacity(
-        2 + 2 + locale.len() + 2 + identifier.len() + 2 + version.len() + 4 + 4 + proto_bytes.len(),
-    );
-
-    buf.put_i16(1); // hardcoded
-    buf.put_i16(locale.len() as i16);
-    buf.put_slice(locale);
-    buf.put_i16(identifier.len() as i16);
-    buf.put_slice(identifier);
-    buf.put_i16(version.len() as i16);
-    buf.put_slice(version);
-    buf.put_i32(request_code);
-    buf.put_i32(proto_bytes.len() as i32);
-    buf.put_slice(&proto_bytes);
-
-    buf.to_vec()
-}
+fn build_cloudflare_region_response(cf: &Cf) -> Option<MlsRegionResponse> {
+    // Cloudflare reports "XX" for unknown countries and "T1" for Tor exit nodes
+    let country_code = cf.country()?.to_uppercase();
+    let country_name = countries::country_name(&country_code)?;
 
-async fn query_apple_wps(request: &AlsLocationRequest) -> Result<Option<AlsLocationResponse>> {
-    let body = build_apple_request_body(request);
-
-    let body_array = js_sys::Uint8Array::from(body.as_slice());
-    
-    let new_headers = web_sys::Headers::new().map_err(|e| Error::JsError(format!("{:?}", e)))?;
-    new_headers.set("Accept", "*/*").map_err(|e| Error::JsError(format!("{:?}", e)))?;
-    new_headers.set("Accept-Language", "en-US,en;q=0.9").map_err(|e| Error::JsError(format!("{:?}", e)))?;
-    new_headers.set("Content-Type", "application/x-www-form-urlencoded").map_err(|e| Error::JsError(format!("{:?}", e)))?;
-    new_headers.set("User-Agent", "locationd/2960.0.57 CFNetwork/3826.500.111.1.1 Darwin/24.4.0").map_err(|e| Error::JsError(format!("{:?}", e)))?;
-    
-    let new_init = web_sys::RequestInit::new();
-    new_init.set_method("POST");
-    new_init.set_body(&body_array);
-    new_init.set_headers(&new_headers);
-    
-    let web_req = web_sys::Request::new_with_str_and_init(GRAPHENEOS_PROXY_URL, &new_init)
-        .map_err(|e| Error::JsError(format!("{:?}", e)))?;
-    
-    let req = Request::from(web_req);
-    let mut response = Fetch::Request(req).send().await?;
-
-    if response.status_code() != 200 {
-        return Ok(None);
-    }
+    Some(MlsRegionResponse {
+        country_code,
+        country_name: country_name.to_string(),
+        fallback: Some("ipf".to_string()),
+    })
+}
 
-    let response_bytes = response.bytes().await?;
-    
-    // Skip first 10 bytes (header)
-    if response_bytes.len() <= 10 {
-        return Ok(None);
-    }
-    
-    let proto_bytes = &response_bytes[10..];
-    let als_response = AlsLocationResponse::decode(proto_bytes)
-        .map_err(|e| Error::RustError(format!("Protobuf decode error: {}", e)))?;
+fn json_response<T: Serialize>(data: &T, status: u16) -> Result<Response> {
+    let body = serde_json::to_string(data)?;
+    let headers = Headers::new();
+    headers.set("Content-Type", "application/json")?;
 
-    Ok(Some(als_response))
+    Ok(Response::builder()
+        .with_status(status)
+        .with_headers(headers)
+        .fixed(body.into_bytes()))
 }
 
-fn estimate_position_from_aps(response: &AlsLocationResponse) -> Option<MlsResponse> {
-    // Collect all valid WiFi positions with their accuracy
-    let mut positions: Vec<(f64, f64, i32)> = Vec::new();
+async fn handle_geolocate(mut req: Request, env: &Env, ctx: &Context) -> Result<Response> {
+    let cf = req.cf().cloned();
+    let mut mls_request = match MlsRequest::parse(&req.text().await?) {
+        Ok(mls_request) => mls_request,
+        Err(reason) => return json_response(&build_parse_error_response(&reason), 400),
+    };
+
+    // Opted-out and randomized APs never reach a backend or the caches, nor do
+    // too few distinct APs to keep a single router from being looked up
+    let aps_filtered = mls_request.drop_unlocatable_wifis()
+        + mls_request.enforce_min_distinct_aps(config::min_distinct_aps(env));
+    let consider_ip = mls_request.consider_ip.unwrap_or(true);
+    let mut location = None;
 
-    for ap in &response.wireless_aps {
-        if let Some(loc) = &ap.location {
-            if let Some((lat, lng, acc)) = loc.to_coordinates() {
-                positions.push((lat, lng, acc));
+    // If we have network data, ask the configured backends unless the same
+    // networks were located recently
+    if mls_request.has_network_data() {
+        let cache = response_cache::ResponseCache::new(env, &mls_request);
+        if let Some(cache) = &cache {
+            location = cache.get().await;
+        }
+
+        if location.is_none() {
+            location = backend::locate(env, &mls_request, ctx).await;
+            if let (Some(cache), Some(response)) = (cache, &location) {
+                cache.put(ctx, response);
             }
         }
     }
 
-    if positions.is_empty() {
-        return None;
+    // Fall back to Cloudflare IP geolocation if allowed
+    if location.is_none() && consider_ip {
+        location = cf.as_ref().and_then(build_cloudflare_response);
     }
 
-    // Simple weighted average by inverse accuracy
-    let mut total_weight = 0.0;
-    let mut weighted_lat = 0.0;
-    let mut weighted_lng = 0.0;
-    let mut min_accuracy = i32::MAX;
-
-    for (lat, lng, acc) in &positions {
-        let weight = 1.0 / (*acc as f64).max(1.0);
-        weighted_lat += lat * weight;
-        weighted_lng += lng * weight;
-        total_weight += weight;
-        min_accuracy = min_accuracy.min(*acc);
+    match location {
+        Some(response) => {
+            let response = MlsResponse {
+                aps_filtered: (aps_filtered > 0).then_some(aps_filtered),
+                ..response
+            };
+            json_response(&response, 200)
+        }
+        // No location available
+        None => json_response(&build_error_response(), 404),
     }
+}
 
-    if total_weight == 0.0 {
-        return None;
-    }
+async fn handle_geosubmit(mut req: Request, env: &Env) -> Result<Response> {
+    let Some(db) = geosubmit::database(env) else {
+        return json_response(&build_service_unavailable_response(), 503);
+    };
+    let submission = match geosubmit::GeosubmitRequest::parse(&req.text().await?) {
+        Ok(submission) => submission,
+        Err(reason) => return json_response(&build_parse_error_response(&reason), 400),
+    };
 
-    let final_lat = weighted_lat / total_weight;
-    let final_lng = weighted_lng / total_weight;
-    
-    // Use the best accuracy among found APs, but at least the minimum
-    let final_accuracy = min_accuracy.max(10) as f64;
+    if let Err(e) = geosubmit::store(&db, &submission).await {
+        console_error!("Failed to store observations: {}", e);
+        return json_response(&build_service_unavailable_response(), 503);
+    }
 
-    Some(MlsResponse {
-        location: Location {
-            lat: final_lat,
-            lng: final_lng,
-        },
-        accuracy: final_accuracy,
-        fallback: None,
-    })
+    // Ichnaea answers a successful submission with an empty object
+    json_response(&serde_json::json!({}), 200)
 }
 
-fn estimate_position_from_cells(response: &AlsLocationResponse) -> Option<MlsResponse> {
-    let mut positions: Vec<(f64, f64, i32)> = Vec::new();
+async fn handle_country(req: Request) -> Result<Response> {
+    if let Some(response) = req.cf().and_then(build_cloudflare_region_response) {
+        return json_response(&response, 200);
+    }
+
+    json_response(&build_error_response(), 404)
+}
 
-    // Collect from all cell tower types
-    for tower in &response.gsm_cell_towers {
-        if let Some(loc) = &tower.location {
-            if let Some(coords) = loc.to_coordinates() {
-                positions.push(coords);
-            }
+#[event(fetch)]
+async fn fetch(req: Request, env: Env, ctx: Context) -> Result<Response> {
+    let path = req.path();
+
+    match (req.method(), path.as_str()) {
+        // "/" is kept as an alias for clients configured before the Ichnaea layout
+        (Method::Post, "/" | "/v1/geolocate") => handle_geolocate(req, &env, &ctx).await,
+        // Ichnaea's deprecated v1 geosubmit answers with a geolocate result
+        (Method::Post, "/v1/geosubmit") => handle_geolocate(req, &env, &ctx).await,
+        (Method::Post, "/v2/geosubmit") => handle_geosubmit(req, &env).await,
+        (Method::Get | Method::Post, "/v1/country") => handle_country(req).await,
+        (_, "/" | "/v1/geolocate" | "/v1/geosubmit" | "/v2/geosubmit" | "/v1/country") => {
+            json_response(
+                &build_error(405, "global", "methodNotAllowed", "Method not allowed"),
+                405,
+            )
         }
+        _ => json_response(&build_error(404, "global", "notFound", "Not found"), 404),
     }
-    for tower in &response.lte_cell_towers {
-        if let Some(loc) = &tower.location {
-            if let Some(coords) = loc.to_coordinates() {
-                positions.push(coords);
-            }
-        }
+}
+
+#[event(scheduled)]
+async fn scheduled(_event: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
+    if let Err(e) = aggregate::run(&env).await {
+        console_error!("Aggregation failed: {}", e);
     }
-    for tower in &response.scdma_cell_towers {
-        if let Some(loc) = &tower.location {
-            if let Some(coords) = loc.to_coordinates() {
-                positions.push(coords);
-            }
-        }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(body: &str) -> String {
+        MlsRequest::parse(body).expect_err(body)
     }
-    for tower in &response.nr5g_cell_towers {
-        if let Some(loc) = &tower.location {
-            if let Some(coords) = loc.to_coordinates() {
-                positions.push(coords);
-            }
+
+    #[test]
+    fn empty_body_is_an_ip_lookup() {
+        for body in ["", "  \n", "{}"] {
+            let request = MlsRequest::parse(body).unwrap();
+            assert!(!request.has_network_data());
+            assert_eq!(request.consider_ip, None);
         }
     }
 
-    if positions.is_empty() {
-        return None;
+    #[test]
+    fn rejects_malformed_json() {
+        for body in ["{", "\"text\"", "{\"cellTowers\": [}", "null x"] {
+            parse_error(body);
+        }
     }
 
-    // Weighted average
-    let mut total_weight = 0.0;
-    let mut weighted_lat = 0.0;
-    let mut weighted_lng = 0.0;
-    let mut min_accuracy = i32::MAX;
-
-    for (lat, lng, acc) in &positions {
-        let weight = 1.0 / (*acc as f64).max(1.0);
-        weighted_lat += lat * weight;
-        weighted_lng += lng * weight;
-        total_weight += weight;
-        min_accuracy = min_accuracy.min(*acc);
+    #[test]
+    fn type_errors_name_the_field() {
+        let error = parse_error(
+            r#"{"cellTowers": [{"mobileCountryCode": 262, "mobileNetworkCode": 1,
+                "locationAreaCode": 1, "cellId": "42"}]}"#,
+        );
+        assert!(error.starts_with("cellTowers[0].cellId: "), "{}", error);
+
+        let error = parse_error(r#"{"wifiAccessPoints": [{"macAddress": "00:11:zz:33:44:55"}]}"#);
+        assert!(
+            error.starts_with("wifiAccessPoints[0].macAddress: "),
+            "{}",
+            error
+        );
     }
 
-    if total_weight == 0.0 {
-        return None;
+    #[test]
+    fn rejects_out_of_range_codes() {
+        let cell = |mcc: i32, mnc: i32| {
+            format!(
+                r#"{{"cellTowers": [{{"mobileCountryCode": {}, "mobileNetworkCode": {},
+                    "locationAreaCode": 1, "cellId": 1}}]}}"#,
+                mcc, mnc
+            )
+        };
+        assert!(parse_error(&cell(1000, 1)).starts_with("cellTowers[0].mobileCountryCode: "));
+        assert!(parse_error(&cell(262, -1)).starts_with("cellTowers[0].mobileNetworkCode: "));
+        assert!(MlsRequest::parse(&cell(262, 1)).is_ok());
+
+        assert!(parse_error(r#"{"homeMobileCountryCode": 1000}"#)
+            .starts_with("homeMobileCountryCode: "));
+        assert!(
+            parse_error(r#"{"homeMobileNetworkCode": -1}"#).starts_with("homeMobileNetworkCode: ")
+        );
     }
 
-    Some(MlsResponse {
-        location: Location {
-            lat: weighted_lat / total_weight,
-            lng: weighted_lng / total_weight,
-        },
-        accuracy: min_accuracy.max(100) as f64,
-        fallback: Some("lacf".to_string()), // Cell tower fallback
-    })
-}
-
-fn json_response<T: Serialize>(data: &T, status: u16) -> Result<Response> {
-    let body = serde_json::to_string(data)?;
-    let headers = Headers::new();
-    headers.set("Content-Type", "application/json")?;
-
-    Ok(Response::builder()
-        .with_status(status)
-        .with_headers(headers)
-        .fixed(body.into_bytes()))
-}
-
-#[event(fetch)]
-async fn fetch(mut req: Request, _env: Env, _ctx: Context) -> Result<Response> {
-    let cf = req.cf().cloned();
+    #[test]
+    fn cell_id_range_depends_on_radio() {
+        let cell = |radio: &str, cell_id: i64| {
+            format!(
+                r#"{{"radioType": "{}", "cellTowers": [{{"mobileCountryCode": 262,
+                    "mobileNetworkCode": 1, "locationAreaCode": 1, "cellId": {}}}]}}"#,
+                radio, cell_id
+            )
+        };
+        let accepts = |radio: &str, cell_id: i64| MlsRequest::parse(&cell(radio, cell_id)).is_ok();
 
-    // Parse request body if present
-    let mls_request: MlsRequest = if req.method() == Method::Post {
-        req.json().await.unwrap_or_default()
-    } else {
-        MlsRequest::default()
-    };
+        assert!(accepts("gsm", GSM_MAX_CELL_ID));
+        assert!(!accepts("gsm", GSM_MAX_CELL_ID + 1));
+        for radio in ["wcdma", "lte"] {
+            assert!(accepts(radio, MAX_CELL_ID));
+            assert!(!accepts(radio, MAX_CELL_ID + 1));
+        }
+        assert!(accepts("nr", (1 << 31) + 1));
+        assert!(accepts("nr", NR_MAX_CELL_ID));
+        assert!(!accepts("nr", NR_MAX_CELL_ID + 1));
+        assert!(!accepts("lte", -1));
 
-    let consider_ip = mls_request.consider_ip.unwrap_or(true);
+        let error = parse_error(&cell("nr", NR_MAX_CELL_ID + 1));
+        assert!(error.starts_with("cellTowers[0].cellId: "), "{}", error);
+    }
 
-    // If we have network data, try Apple WPS via GrapheneOS proxy
-    if mls_request.has_network_data() {
-        let bssids = mls_request.get_bssids();
-        let cells = mls_request.get_cells(&mls_request.radio_type);
+    fn wifi_request(macs: &[&str]) -> MlsRequest {
+        let aps: Vec<String> = macs
+            .iter()
+            .map(|mac| format!(r#"{{"macAddress": "{}"}}"#, mac))
+            .collect();
+        MlsRequest::parse(&format!(r#"{{"wifiAccessPoints": [{}]}}"#, aps.join(","))).unwrap()
+    }
 
-        let apple_request = if !bssids.is_empty() && !cells.is_empty() {
-            AlsLocationRequest::new_combined_request(&bssids, cells, 100, 25)
-        } else if !bssids.is_empty() {
-            AlsLocationRequest::new_wifi_request(&bssids, 100)
-        } else {
-            AlsLocationRequest::new_cell_request(cells, 25)
-        };
+    #[test]
+    fn single_router_wifi_data_is_dropped() {
+        // The 2.4 and 5 GHz radios of one router
+        let mut request = wifi_request(&["00:11:22:33:44:50", "00:11:22:33:44:51"]);
+        assert!(request.has_wifi_data());
 
-        if let Ok(Some(apple_response)) = query_apple_wps(&apple_request).await {
-            // Try WiFi first (more accurate), then cells
-            if let Some(response) = estimate_position_from_aps(&apple_response) {
-                return json_response(&response, 200);
-            }
-            if let Some(response) = estimate_position_from_cells(&apple_response) {
-                return json_response(&response, 200);
-            }
-        }
+        assert_eq!(request.enforce_min_distinct_aps(2), 2);
+        assert!(request.wifi_access_points.is_none());
+        assert!(!request.has_network_data());
     }
 
-    // Fall back to Cloudflare IP geolocation if allowed
-    if consider_ip {
-        if let Some(cf) = cf.as_ref() {
-            if let Some(response) = build_cloudflare_response(cf) {
-                return json_response(&response, 200);
-            }
-        }
+    #[test]
+    fn distinct_aps_are_counted_against_the_minimum() {
+        // Three BSSIDs, two of them bands of the same router
+        let macs = [
+            "00:11:22:33:44:50",
+            "00:11:22:33:44:51",
+            "00:25:9c:52:1c:6a",
+        ];
+
+        let mut request = wifi_request(&macs);
+        assert_eq!(request.enforce_min_distinct_aps(2), 0);
+        assert_eq!(request.wifi_access_points.as_ref().map(Vec::len), Some(3));
+
+        let mut request = wifi_request(&macs);
+        assert_eq!(request.enforce_min_distinct_aps(3), 3);
+        assert!(request.wifi_access_points.is_none());
+
+        // Three physical APs meet a minimum of three
+        let mut request = wifi_request(&[
+            "00:11:22:33:44:50",
+            "00:25:9c:52:1c:6a",
+            "a4:2b:b0:11:22:33",
+        ]);
+        assert_eq!(request.enforce_min_distinct_aps(3), 0);
     }
-
-    // No location available
-    json_response(&build_error_response(), 404)
 }
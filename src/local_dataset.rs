@@ -0,0 +1,106 @@
+// Offline lookup from a dataset of AP and cell tower positions in Workers KV, such
+// as harvested or aggregated observations, so a deployment can run without upstreams
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use worker::{console_warn, Env, KvStore};
+
+use crate::apple_wps::{AlsLocation, AlsLocationResponse};
+use crate::estimate::estimate_position;
+use crate::position_cache::{network_keys, LOCAL_ALTITUDE_SCALE};
+use crate::{MlsRequest, MlsResponse};
+
+const KV_BINDING: &str = "LOCAL_DATASET";
+
+/// A dataset record, stored as JSON under the same keys as the position cache:
+/// `wifi:aa:bb:cc:dd:ee:ff` or `cell:<radio>:<mcc>:<mnc>:<lac>:<cellId>`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DatasetRecord {
+    pub lat: f64,
+    pub lng: f64,
+    /// Meters
+    pub accuracy: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub altitude: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub altitude_accuracy: Option<f64>,
+}
+
+impl DatasetRecord {
+    /// Converts to Apple's fixed point representation so the record goes through the
+    /// same estimation as an Apple WPS answer
+    fn to_als(&self) -> Option<AlsLocation> {
+        let valid = (-90.0..=90.0).contains(&self.lat)
+            && (-180.0..=180.0).contains(&self.lng)
+            && self.accuracy.is_finite()
+            && self.accuracy >= 0.0;
+        if !valid {
+            return None;
+        }
+
+        Some(AlsLocation {
+            latitude: (self.lat * 1e8).round() as i64,
+            longitude: (self.lng * 1e8).round() as i64,
+            accuracy: self.accuracy.round() as i32,
+            altitude: self.altitude.map(|a| (a * 100.0).round() as i32),
+            vertical_accuracy: self.altitude_accuracy.map(|v| (v * 100.0).round() as i32),
+            ..Default::default()
+        })
+    }
+}
+
+pub struct LocalDataset {
+    kv: KvStore,
+}
+
+impl LocalDataset {
+    /// `None` when the `LOCAL_DATASET` KV namespace is not bound
+    pub fn new(env: &Env) -> Option<Self> {
+        Some(LocalDataset {
+            kv: env.kv(KV_BINDING).ok()?,
+        })
+    }
+
+    pub async fn put(&self, key: &str, record: &DatasetRecord) -> worker::Result<()> {
+        self.kv.put(key, record)?.execute().await?;
+        Ok(())
+    }
+
+    pub async fn remove(&self, key: &str) -> worker::Result<()> {
+        self.kv.delete(key).await?;
+        Ok(())
+    }
+
+    /// Locates the request from the records of the networks it contains. Unlike the
+    /// position cache, networks missing from the dataset are simply left out; `None`
+    /// means none of them could be used
+    pub async fn locate(&self, request: &MlsRequest) -> Option<MlsResponse> {
+        let wifis = request.get_wifis();
+        let cells = request.get_cells(&request.radio_type);
+        let keys = network_keys(&wifis, &cells)?;
+
+        let reads = keys
+            .iter()
+            .map(|key| self.kv.get(key).json::<DatasetRecord>());
+        let locations: Vec<Option<AlsLocation>> = join_all(reads)
+            .await
+            .into_iter()
+            .map(|result| match result {
+                Ok(record) => record.and_then(|r| r.to_als()),
+                Err(e) => {
+                    console_warn!("Local dataset lookup failed: {}", e);
+                    None
+                }
+            })
+            .collect();
+        if locations.iter().all(Option::is_none) {
+            return None;
+        }
+
+        let response = AlsLocationResponse::from_locations(&wifis, &cells, locations);
+        estimate_position(&response, request, LOCAL_ALTITUDE_SCALE).map(|response| MlsResponse {
+            backend: Some("local".to_string()),
+            ..response
+        })
+    }
+}
@@ -0,0 +1,194 @@
+// MAC addresses (BSSIDs) in the notations clients and Apple use
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// A MAC address, displayed in the canonical lowercase `aa:bb:cc:dd:ee:ff` form
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MacAddress([u8; 6]);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MacAddressError {
+    Empty,
+    InvalidCharacter(char),
+    MixedSeparators,
+    /// Not six octets in one of the accepted notations
+    InvalidFormat,
+}
+
+impl fmt::Display for MacAddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MacAddressError::Empty => write!(f, "empty MAC address"),
+            MacAddressError::InvalidCharacter(c) => {
+                write!(f, "invalid character {:?} in MAC address", c)
+            }
+            MacAddressError::MixedSeparators => write!(f, "MAC address mixes separators"),
+            MacAddressError::InvalidFormat => write!(
+                f,
+                "expected a MAC address as aa:bb:cc:dd:ee:ff, aa-bb-cc-dd-ee-ff, \
+                 aabb.ccdd.eeff or aabbccddeeff"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MacAddressError {}
+
+impl MacAddress {
+    /// The address as a 48-bit number, so neighboring addresses are adjacent
+    pub fn to_u64(self) -> u64 {
+        self.0.iter().fold(0, |mac, octet| mac << 8 | *octet as u64)
+    }
+
+    /// Group addresses, including broadcast
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 0x01 != 0
+    }
+
+    /// Addresses not assigned by the manufacturer, such as randomized MACs
+    pub fn is_locally_administered(&self) -> bool {
+        self.0[0] & 0x02 != 0
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == [0; 6]
+    }
+}
+
+/// Parses `groups` hex groups of `digits` digits each into the octets
+fn parse_groups<'a>(
+    groups: impl Iterator<Item = &'a str>,
+    count: usize,
+    digits: std::ops::RangeInclusive<usize>,
+) -> Result<[u8; 6], MacAddressError> {
+    let groups: Vec<&str> = groups.collect();
+    if groups.len() != count || !groups.iter().all(|g| digits.contains(&g.len())) {
+        return Err(MacAddressError::InvalidFormat);
+    }
+
+    let bytes_per_group = 6 / count;
+    let mut octets = [0u8; 6];
+    for (i, group) in groups.iter().enumerate() {
+        let value = u64::from_str_radix(group, 16).map_err(|_| MacAddressError::InvalidFormat)?;
+        for j in 0..bytes_per_group {
+            let shift = 8 * (bytes_per_group - 1 - j);
+            octets[i * bytes_per_group + j] = (value >> shift) as u8;
+        }
+    }
+    Ok(octets)
+}
+
+impl FromStr for MacAddress {
+    type Err = MacAddressError;
+
+    /// Accepts `aa:bb:cc:dd:ee:ff` and `aa-bb-cc-dd-ee-ff` (octets may drop their
+    /// leading zero, as in Apple's `0:11:2:...`), Cisco's `aabb.ccdd.eeff` and bare
+    /// `aabbccddeeff`, in any case and with surrounding whitespace
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(MacAddressError::Empty);
+        }
+        if let Some(c) = s
+            .chars()
+            .find(|c| !c.is_ascii_hexdigit() && !matches!(c, ':' | '-' | '.'))
+        {
+            return Err(MacAddressError::InvalidCharacter(c));
+        }
+
+        let has = |separator: char| s.contains(separator);
+        let octets = match (has(':'), has('-'), has('.')) {
+            (false, false, false) if s.len() == 12 => {
+                parse_groups((0..6).map(|i| &s[2 * i..2 * i + 2]), 6, 2..=2)?
+            }
+            (false, false, false) => return Err(MacAddressError::InvalidFormat),
+            (true, false, false) => parse_groups(s.split(':'), 6, 1..=2)?,
+            (false, true, false) => parse_groups(s.split('-'), 6, 1..=2)?,
+            (false, false, true) => parse_groups(s.split('.'), 3, 4..=4)?,
+            _ => return Err(MacAddressError::MixedSeparators),
+        };
+
+        Ok(MacAddress(octets))
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+impl Serialize for MacAddress {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for MacAddress {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse()
+            .map_err(|e| serde::de::Error::custom(format!("{:?}: {}", s, e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CANONICAL: &str = "00:11:22:aa:bb:0c";
+
+    #[test]
+    fn parses_common_notations() {
+        for input in [
+            "00:11:22:aa:bb:0c",
+            "00:11:22:AA:BB:0C",
+            "00-11-22-aa-bb-0c",
+            "001122aabb0c",
+            "0011.22aa.bb0c",
+            "  00:11:22:aa:bb:0c\n",
+            // Apple drops leading zeros
+            "0:11:22:aa:bb:c",
+        ] {
+            let mac: MacAddress = input.parse().unwrap();
+            assert_eq!(mac.to_string(), CANONICAL, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_addresses() {
+        for (input, error) in [
+            ("", MacAddressError::Empty),
+            ("   ", MacAddressError::Empty),
+            ("00:11:22:aa:bb:zz", MacAddressError::InvalidCharacter('z')),
+            ("00 11 22 aa bb 0c", MacAddressError::InvalidCharacter(' ')),
+            ("00:11:22-aa:bb:0c", MacAddressError::MixedSeparators),
+            ("00:11:22:aa:bb", MacAddressError::InvalidFormat),
+            ("00:11:22:aa:bb:0c:0d", MacAddressError::InvalidFormat),
+            ("00:11:22:aa:bb:", MacAddressError::InvalidFormat),
+            ("000:11:22:aa:bb:0c", MacAddressError::InvalidFormat),
+            ("001122aabb0", MacAddressError::InvalidFormat),
+            ("0011.22aa.bb0", MacAddressError::InvalidFormat),
+        ] {
+            assert_eq!(input.parse::<MacAddress>(), Err(error), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn address_kinds() {
+        let mac = |s: &str| s.parse::<MacAddress>().unwrap();
+        assert!(mac("ff:ff:ff:ff:ff:ff").is_multicast());
+        assert!(mac("01:00:5e:00:00:01").is_multicast());
+        assert!(mac("da:a1:19:00:00:01").is_locally_administered());
+        assert!(mac("00:00:00:00:00:00").is_zero());
+        assert!(!mac(CANONICAL).is_multicast());
+        assert!(!mac(CANONICAL).is_locally_administered());
+    }
+}
@@ -0,0 +1,349 @@
+// Positions of the APs and cell towers Apple WPS returns, cached in Workers KV so
+// requests for already known networks can be answered without going upstream.
+// Networks Apple does not know are remembered too and left out of later requests
+use std::time::Duration;
+
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use worker::{console_warn, Env, KvStore};
+
+use crate::apple_wps::{
+    AlsLocation, AlsLocationResponse, CellRequest, WifiAltitudeScale, WifiRequest,
+};
+use crate::config;
+use crate::mac::MacAddress;
+
+const KV_BINDING: &str = "POSITION_CACHE";
+// Prefix of the negative entries, which have no value
+const UNKNOWN_PREFIX: &str = "unknown:";
+
+/// An `AlsLocation` with its altitude converted to meters, so entries do not
+/// depend on the altitude scale of the request that produced them
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct CachedLocation {
+    latitude: i64,
+    longitude: i64,
+    accuracy: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    altitude: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    vertical_accuracy: Option<f64>,
+}
+
+impl CachedLocation {
+    /// `None` for locations Apple does not know, those are not cached
+    fn from_als(location: &AlsLocation, scale: WifiAltitudeScale) -> Option<Self> {
+        location.to_coordinates()?;
+        let altitude = location.to_altitude(scale);
+        Some(CachedLocation {
+            latitude: location.latitude,
+            longitude: location.longitude,
+            accuracy: location.accuracy,
+            altitude: altitude.map(|(altitude, _)| altitude),
+            vertical_accuracy: altitude.map(|(_, vertical)| vertical),
+        })
+    }
+
+    /// Rebuilds the location with the altitude in `LOCAL_ALTITUDE_SCALE`
+    fn to_als(&self) -> AlsLocation {
+        AlsLocation {
+            latitude: self.latitude,
+            longitude: self.longitude,
+            accuracy: self.accuracy,
+            altitude: self.altitude.map(|a| (a * 100.0).round() as i32),
+            vertical_accuracy: self.vertical_accuracy.map(|v| (v * 100.0).round() as i32),
+            ..Default::default()
+        }
+    }
+}
+
+/// Altitude scale of the responses rebuilt from the cache
+pub const LOCAL_ALTITUDE_SCALE: WifiAltitudeScale = WifiAltitudeScale::TenToThe2;
+
+pub fn wifi_key(mac: MacAddress) -> String {
+    format!("wifi:{}", mac)
+}
+
+/// `radio` is the MLS radio type the tower is requested with: gsm, wcdma, lte or nr
+pub fn cell_key(radio: &str, mcc: i32, mnc: i32, lac: i32, cell_id: i64) -> String {
+    format!("cell:{}:{}:{}:{}:{}", radio, mcc, mnc, lac, cell_id)
+}
+
+/// KV keys of the requested APs followed by those of the cell towers, `None` if a
+/// BSSID cannot be parsed
+pub fn network_keys(wifis: &[WifiRequest], cells: &[CellRequest]) -> Option<Vec<String>> {
+    let mut keys: Vec<String> = Vec::new();
+    for wifi in wifis {
+        keys.push(wifi_key(wifi.bssid.parse().ok()?));
+    }
+    for cell in cells {
+        keys.push(cell_key(
+            &cell.radio_type,
+            cell.mcc,
+            cell.mnc,
+            cell.lac,
+            cell.cell_id,
+        ));
+    }
+    Some(keys)
+}
+
+#[derive(Clone)]
+pub struct PositionCache {
+    kv: KvStore,
+    ttl: Duration,
+    unknown_ttl: Duration,
+}
+
+impl PositionCache {
+    /// `None` when the `POSITION_CACHE` KV namespace is not bound
+    pub fn new(env: &Env) -> Option<Self> {
+        Some(PositionCache {
+            kv: env.kv(KV_BINDING).ok()?,
+            ttl: config::position_cache_ttl(env),
+            unknown_ttl: config::unknown_cache_ttl(env),
+        })
+    }
+
+    /// Caches every AP and cell tower in an Apple WPS response, including the
+    /// surrounding ones the client did not report. Those Apple marks as unknown are
+    /// cached as such. Entries that are already cached are not rewritten, KV allows
+    /// one write per second to a key and plans have a daily write quota
+    pub async fn store(&self, response: &AlsLocationResponse, scale: WifiAltitudeScale) {
+        // Unknown networks are stored under their negative key
+        let mut entries: Vec<(String, Option<CachedLocation>)> = Vec::new();
+        let mut push = |key: Option<String>, location: &Option<AlsLocation>| {
+            if let (Some(key), Some(location)) = (key, location) {
+                entries.push(match CachedLocation::from_als(location, scale) {
+                    Some(location) => (key, Some(location)),
+                    None => (format!("{}{}", UNKNOWN_PREFIX, key), None),
+                });
+            }
+        };
+
+        for ap in &response.wireless_aps {
+            push(ap.mac_id.parse().ok().map(wifi_key), &ap.location);
+        }
+        for tower in &response.gsm_cell_towers {
+            let key = cell_key(
+                "gsm",
+                tower.mcc,
+                tower.mnc,
+                tower.lac_id,
+                tower.cell_id as i64,
+            );
+            push(Some(key), &tower.location);
+        }
+        for tower in &response.lte_cell_towers {
+            let key = cell_key(
+                "lte",
+                tower.mcc.unwrap_or_default(),
+                tower.mnc.unwrap_or_default(),
+                tower.tac_id.unwrap_or_default(),
+                tower.cell_id.unwrap_or_default() as i64,
+            );
+            push(Some(key), &tower.location);
+        }
+        for tower in &response.scdma_cell_towers {
+            let key = cell_key(
+                "wcdma",
+                tower.mcc,
+                tower.mnc,
+                tower.lac_id,
+                tower.cell_id as i64,
+            );
+            push(Some(key), &tower.location);
+        }
+        for tower in &response.nr5g_cell_towers {
+            let key = cell_key(
+                "nr",
+                tower.mcc.unwrap_or_default(),
+                tower.mnc.unwrap_or_default(),
+                tower.tac_id.unwrap_or_default(),
+                tower.cell_id.unwrap_or_default(),
+            );
+            push(Some(key), &tower.location);
+        }
+
+        let cached = join_all(entries.iter().map(|(key, _)| self.exists(key))).await;
+        let entries: Vec<_> = entries
+            .into_iter()
+            .zip(cached)
+            .filter_map(|(entry, cached)| (!cached).then_some(entry))
+            .collect();
+
+        let writes = entries.iter().map(|(key, location)| async move {
+            match location {
+                Some(location) => {
+                    self.kv
+                        .put(key, location)?
+                        .expiration_ttl(self.ttl.as_secs())
+                        .execute()
+                        .await
+                }
+                None => {
+                    self.kv
+                        .put(key, "")?
+                        .expiration_ttl(self.unknown_ttl.as_secs())
+                        .execute()
+                        .await
+                }
+            }
+        });
+        let failed = join_all(writes)
+            .await
+            .into_iter()
+            .filter(|r| r.is_err())
+            .count();
+        if failed > 0 {
+            console_warn!("Failed to cache {} of {} positions", failed, entries.len());
+        }
+    }
+
+    /// Removes the APs and cell towers Apple recently reported as unknown
+    pub async fn strip_unknown(&self, wifis: &mut Vec<WifiRequest>, cells: &mut Vec<CellRequest>) {
+        let wifi_keys: Vec<Option<String>> = wifis
+            .iter()
+            .map(|wifi| wifi.bssid.parse().ok().map(wifi_key))
+            .collect();
+        let cell_keys: Vec<Option<String>> = cells
+            .iter()
+            .map(|cell| {
+                Some(cell_key(
+                    &cell.radio_type,
+                    cell.mcc,
+                    cell.mnc,
+                    cell.lac,
+                    cell.cell_id,
+                ))
+            })
+            .collect();
+
+        let wifi_unknown = self.unknown(&wifi_keys).await;
+        let cell_unknown = self.unknown(&cell_keys).await;
+        let mut unknown = wifi_unknown.iter();
+        wifis.retain(|_| !unknown.next().copied().unwrap_or_default());
+        let mut unknown = cell_unknown.iter();
+        cells.retain(|_| !unknown.next().copied().unwrap_or_default());
+    }
+
+    /// Whether each key has a negative entry. Lookup errors count as not unknown
+    async fn unknown(&self, keys: &[Option<String>]) -> Vec<bool> {
+        let reads = keys.iter().map(|key| async move {
+            match key {
+                Some(key) => self.exists(&format!("{}{}", UNKNOWN_PREFIX, key)).await,
+                None => false,
+            }
+        });
+        join_all(reads).await
+    }
+
+    /// Whether `key` has an entry. Lookup errors count as no entry
+    async fn exists(&self, key: &str) -> bool {
+        let entry = self.kv.get(key).text().await;
+        entry.is_ok_and(|entry| entry.is_some())
+    }
+
+    /// Rebuilds an Apple WPS response for the requested APs and cell towers, `None`
+    /// unless every one of them is cached, with a position or as unknown
+    pub async fn lookup(
+        &self,
+        wifis: &[WifiRequest],
+        cells: &[CellRequest],
+    ) -> Option<AlsLocationResponse> {
+        let keys = network_keys(wifis, cells)?;
+        if keys.is_empty() {
+            return None;
+        }
+
+        let reads = keys
+            .iter()
+            .map(|key| self.kv.get(key).json::<CachedLocation>());
+        let mut locations = Vec::with_capacity(keys.len());
+        let mut missing = Vec::new();
+        for (key, result) in keys.iter().zip(join_all(reads).await) {
+            match result {
+                Ok(location) => {
+                    if location.is_none() {
+                        missing.push(Some(key.clone()));
+                    }
+                    locations.push(location.map(|location| location.to_als()));
+                }
+                Err(e) => {
+                    console_warn!("Position cache lookup failed: {}", e);
+                    return None;
+                }
+            }
+        }
+
+        // Networks Apple reported as unknown are resolved too, only without a position
+        if !self
+            .unknown(&missing)
+            .await
+            .into_iter()
+            .all(|unknown| unknown)
+        {
+            return None;
+        }
+
+        Some(AlsLocationResponse::from_locations(wifis, cells, locations))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(altitude: Option<i32>, vertical_accuracy: Option<i32>) -> AlsLocation {
+        AlsLocation {
+            latitude: 5_251_630_000,
+            longitude: 1_337_770_000,
+            accuracy: 25,
+            altitude,
+            vertical_accuracy,
+            ..Default::default()
+        }
+    }
+
+    /// Stores a location as the cache does and reads it back
+    fn round_trip(location: &AlsLocation, scale: WifiAltitudeScale) -> AlsLocation {
+        let cached = CachedLocation::from_als(location, scale).unwrap();
+        let json = serde_json::to_string(&cached).unwrap();
+        serde_json::from_str::<CachedLocation>(&json)
+            .unwrap()
+            .to_als()
+    }
+
+    #[test]
+    fn altitudes_survive_the_round_trip() {
+        let restored = round_trip(
+            &location(Some(4150), Some(300)),
+            WifiAltitudeScale::TenToThe2,
+        );
+        assert_eq!(restored.to_coordinates(), Some((52.5163, 13.3777, 25)));
+        assert_eq!(
+            restored.to_altitude(LOCAL_ALTITUDE_SCALE),
+            Some((41.5, 3.0))
+        );
+
+        // Entries from requests in meters are read back in the local scale too
+        let restored = round_trip(&location(Some(41), Some(3)), WifiAltitudeScale::None);
+        assert_eq!(
+            restored.to_altitude(LOCAL_ALTITUDE_SCALE),
+            Some((41.0, 3.0))
+        );
+
+        let restored = round_trip(&location(None, None), WifiAltitudeScale::TenToThe2);
+        assert_eq!(restored.to_altitude(LOCAL_ALTITUDE_SCALE), None);
+    }
+
+    #[test]
+    fn unknown_locations_are_not_cached() {
+        let unknown = AlsLocation {
+            latitude: -18_000_000_000,
+            longitude: -18_000_000_000,
+            ..Default::default()
+        };
+        assert!(CachedLocation::from_als(&unknown, WifiAltitudeScale::TenToThe2).is_none());
+    }
+}
@@ -0,0 +1,147 @@
+// Whole geolocate responses cached through the Workers Cache API, for devices that
+// repeat the same scan every few minutes. The Cache API stores nothing on
+// `*.workers.dev`, only on routes and custom domains of a zone
+use std::time::Duration;
+
+use worker::{console_warn, Cache, Context, Env, Headers, Response};
+
+use crate::config;
+use crate::{MlsRequest, MlsResponse};
+
+// Cache API keys are URLs, this host is never fetched
+const CACHE_KEY_BASE: &str = "https://geolocate.response-cache";
+
+pub struct ResponseCache {
+    cache: Cache,
+    key: String,
+    ttl: Duration,
+}
+
+/// Canonical key for the networks in a request: the sorted, deduplicated normalized
+/// BSSIDs and cell identities. Signal strengths, ages, `considerIp` and the client
+/// address do not affect it
+pub fn cache_key(request: &MlsRequest) -> String {
+    let mut wifis: Vec<String> = request
+        .get_wifis()
+        .into_iter()
+        .map(|wifi| wifi.bssid)
+        .collect();
+    wifis.sort();
+    wifis.dedup();
+
+    let mut cells: Vec<String> = request
+        .get_cells(&request.radio_type)
+        .into_iter()
+        .map(|cell| {
+            format!(
+                "{}:{}:{}:{}:{}",
+                cell.radio_type, cell.mcc, cell.mnc, cell.lac, cell.cell_id
+            )
+        })
+        .collect();
+    cells.sort();
+    cells.dedup();
+
+    format!(
+        "{}/geolocate/wifi/{}/cell/{}",
+        CACHE_KEY_BASE,
+        wifis.join(","),
+        cells.join(",")
+    )
+}
+
+impl ResponseCache {
+    /// `None` when `RESPONSE_CACHE_TTL_SECS` is 0
+    pub fn new(env: &Env, request: &MlsRequest) -> Option<Self> {
+        let ttl = config::response_cache_ttl(env);
+        if ttl.is_zero() {
+            return None;
+        }
+
+        Some(ResponseCache {
+            cache: Cache::default(),
+            key: cache_key(request),
+            ttl,
+        })
+    }
+
+    /// The cached response, reporting `"backend": "response-cache"` instead of the
+    /// backend that originally located it
+    pub async fn get(&self) -> Option<MlsResponse> {
+        let mut cached = match self.cache.get(self.key.as_str(), true).await {
+            Ok(cached) => cached?,
+            Err(e) => {
+                console_warn!("Response cache lookup failed: {}", e);
+                return None;
+            }
+        };
+        let response: MlsResponse = cached.json().await.ok()?;
+        Some(MlsResponse {
+            backend: Some("response-cache".to_string()),
+            ..response
+        })
+    }
+
+    /// Caches `response` after the worker has answered, through `ctx`
+    pub fn put(self, ctx: &Context, response: &MlsResponse) {
+        let cached = match self.cached_response(response) {
+            Ok(cached) => cached,
+            Err(e) => {
+                console_warn!("Failed to cache response: {}", e);
+                return;
+            }
+        };
+        ctx.wait_until(async move {
+            if let Err(e) = self.cache.put(self.key.as_str(), cached).await {
+                console_warn!("Failed to cache response: {}", e);
+            }
+        });
+    }
+
+    fn cached_response(&self, response: &MlsResponse) -> worker::Result<Response> {
+        let headers = Headers::new();
+        headers.set("Content-Type", "application/json")?;
+        headers.set(
+            "Cache-Control",
+            &format!("public, max-age={}", self.ttl.as_secs()),
+        )?;
+        Ok(Response::from_json(response)?.with_headers(headers))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(body: &str) -> String {
+        cache_key(&MlsRequest::parse(body).unwrap())
+    }
+
+    #[test]
+    fn key_ignores_order_signals_and_consider_ip() {
+        let request = key(r#"{"considerIp": true, "radioType": "lte",
+                "wifiAccessPoints": [
+                    {"macAddress": "00:11:22:33:44:55", "signalStrength": -60},
+                    {"macAddress": "00:25:9c:52:1c:6a", "signalStrength": -75}],
+                "cellTowers": [
+                    {"mobileCountryCode": 262, "mobileNetworkCode": 1,
+                     "locationAreaCode": 1234, "cellId": 567890, "signalStrength": -90},
+                    {"mobileCountryCode": 262, "mobileNetworkCode": 1,
+                     "locationAreaCode": 1234, "cellId": 567891}]}"#);
+        let reordered = key(r#"{"considerIp": false,
+                "wifiAccessPoints": [
+                    {"macAddress": "00-25-9C-52-1C-6A", "signalStrength": -50, "age": 3000},
+                    {"macAddress": "001122334455"}],
+                "cellTowers": [
+                    {"radioType": "lte", "mobileCountryCode": 262, "mobileNetworkCode": 1,
+                     "locationAreaCode": 1234, "cellId": 567891, "signalStrength": -70},
+                    {"mobileCountryCode": 262, "mobileNetworkCode": 1,
+                     "locationAreaCode": 1234, "cellId": 567890}]}"#);
+        assert_eq!(request, reordered);
+
+        let other_network = key(r#"{"wifiAccessPoints": [
+                    {"macAddress": "00:11:22:33:44:55"},
+                    {"macAddress": "00:25:9c:52:1c:6b"}]}"#);
+        assert_ne!(request, other_network);
+    }
+}